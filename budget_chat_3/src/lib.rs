@@ -0,0 +1,33 @@
+//! Wording of the budget chat protocol, shared by this server and the chat
+//! hosted behind the insecure sockets layer. Lines are returned without their
+//! trailing newline.
+
+pub const WELCOME: &str = "Welcome to budgetchat! What shall I call you?";
+
+/// User and room names are one or more ASCII letters or digits.
+pub fn is_valid_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric())
+}
+
+/// Tells a new arrival who else is in the room, in name order.
+pub fn room_contains<'a>(names: impl IntoIterator<Item = &'a str>) -> String {
+    let mut names: Vec<&str> = names.into_iter().collect();
+    names.sort();
+    format!("* The room contains: {}", names.join(", "))
+}
+
+pub fn entered(name: &str) -> String {
+    format!("* {name} has entered the room")
+}
+
+pub fn left(name: &str) -> String {
+    format!("* {name} has left the room")
+}
+
+pub fn name_taken(name: &str) -> String {
+    format!("* The name {name} is taken")
+}
+
+pub fn message(name: &str, text: &str) -> String {
+    format!("[{name}] {text}")
+}
@@ -1,31 +1,97 @@
+use budget_chat_3::{entered, is_valid_name, left, name_taken, room_contains, WELCOME};
 use std::{
-    collections::HashMap,
-    io::{BufRead, BufReader, BufWriter, Write},
-    net::{TcpListener, TcpStream},
+    collections::{BTreeMap, HashMap},
     sync::{Arc, Mutex},
-    thread,
 };
+use tokio::{
+    io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
+    net::{
+        tcp::{OwnedReadHalf, OwnedWriteHalf},
+        TcpListener, TcpStream,
+    },
+    sync::{
+        mpsc,
+        oneshot::{self, error::TryRecvError},
+    },
+};
+
+/// Room every user starts in. Clients that never send a command only ever
+/// see this room, like the original single-room chat.
+const DEFAULT_ROOM: &str = "lobby";
+
+/// Lines a client may fall behind by before it is disconnected.
+const QUEUE_LENGTH: usize = 128;
+
+struct Client {
+    room: String,
+    outbox: mpsc::Sender<String>,
+    // Dropped along with the client, which tells its connection to close.
+    _connected: oneshot::Sender<()>,
+}
 
-fn prompt_username(stream: TcpStream) -> Option<String> {
-    let mut reader = BufReader::new(stream.try_clone().unwrap());
-    let mut writer = BufWriter::new(stream.try_clone().unwrap());
+type Clients = Arc<Mutex<HashMap<String, Client>>>;
+
+#[derive(Debug, PartialEq, Eq)]
+enum Command {
+    Join(String),
+    Leave,
+    Rooms,
+    Who,
+    Msg(String, String),
+    Nick(String),
+    Message(String),
+}
+
+fn parse_command(message: &str) -> Result<Command, String> {
+    if !message.starts_with('/') {
+        return Ok(Command::Message(String::from(message)));
+    }
 
-    let res = writer.write_all("Welcome to budgetchat! What shall I call you?\n".as_bytes());
-    let _ = writer.flush();
+    let (command, rest) = message
+        .split_once(char::is_whitespace)
+        .unwrap_or((message, ""));
+    let rest = rest.trim();
+
+    match command {
+        "/join" if is_valid_name(rest) => Ok(Command::Join(String::from(rest))),
+        "/join" => Err(String::from("Usage: /join <room>")),
+        "/leave" if rest.is_empty() => Ok(Command::Leave),
+        "/leave" => Err(String::from("Usage: /leave")),
+        "/rooms" if rest.is_empty() => Ok(Command::Rooms),
+        "/rooms" => Err(String::from("Usage: /rooms")),
+        "/who" if rest.is_empty() => Ok(Command::Who),
+        "/who" => Err(String::from("Usage: /who")),
+        "/msg" => match rest.split_once(char::is_whitespace) {
+            Some((user, text)) if is_valid_name(user) => {
+                Ok(Command::Msg(String::from(user), String::from(text.trim())))
+            }
+            _ => Err(String::from("Usage: /msg <user> <text>")),
+        },
+        "/nick" if is_valid_name(rest) => Ok(Command::Nick(String::from(rest))),
+        "/nick" => Err(String::from("Usage: /nick <name>")),
+        // Anything else is chat, so plain clients can still say "/shrug".
+        _ => Ok(Command::Message(String::from(message))),
+    }
+}
+
+async fn prompt_username(
+    reader: &mut BufReader<OwnedReadHalf>,
+    writer: &mut OwnedWriteHalf,
+) -> Option<String> {
+    let res = writer.write_all(format!("{WELCOME}\n").as_bytes()).await;
     if res.is_err() {
         return None;
     }
 
     let mut username = String::new();
-    let result = reader.read_line(&mut username);
+    let result = reader.read_line(&mut username).await;
 
     println!("{username}");
 
     match result {
         Ok(_) => {
             let username = username.trim();
-            let re = regex::Regex::new(r"^[a-zA-Z0-9]+$");
-            if !username.is_empty() && re.unwrap().is_match(username) {
+            if is_valid_name(username) {
                 Some(String::from(username))
             } else {
                 None
@@ -35,44 +101,170 @@ fn prompt_username(stream: TcpStream) -> Option<String> {
     }
 }
 
-fn add_new_user(
-    username: &String,
-    stream: &mut TcpStream,
-    clients: &Arc<Mutex<HashMap<String, TcpStream>>>,
-) {
-    // Announance current user
-    clients
-        .lock()
-        .unwrap()
-        .values_mut()
-        .for_each(|other_client| {
-            let _ =
-                other_client.write_all(format!("* {} has entered the room\n", username).as_bytes());
-            let _ = other_client.flush();
-        });
+/// Queues `line` for each of `recipients`. Anyone whose queue is full is
+/// dropped from the chat rather than holding up everyone else.
+fn deliver(clients: &mut HashMap<String, Client>, recipients: Vec<String>, line: &str) {
+    for name in recipients {
+        let Some(client) = clients.get(&name) else {
+            continue;
+        };
+        if client.outbox.try_send(String::from(line)).is_ok() {
+            continue;
+        }
+
+        println!("{name} is not keeping up, disconnecting");
+        if let Some(client) = clients.remove(&name) {
+            announce(clients, &client.room, &name, &left(&name));
+        }
+    }
+}
+
+/// Sends `line` to the user themself.
+fn reply(clients: &mut HashMap<String, Client>, username: &str, line: &str) {
+    deliver(clients, vec![String::from(username)], line);
+}
+
+/// Sends `line` to everyone in `room` except `current_user`.
+fn announce(clients: &mut HashMap<String, Client>, room: &str, current_user: &str, line: &str) {
+    let recipients = clients
+        .iter()
+        .filter(|(name, client)| name.as_str() != current_user && client.room == room)
+        .map(|(name, _)| name.clone())
+        .collect();
+    deliver(clients, recipients, line);
+}
+
+/// Announces `username` to `room` and tells them who is already there.
+fn enter_room(username: &str, room: &str, clients: &mut HashMap<String, Client>) {
+    announce(clients, room, username, &entered(username));
 
     // List online users
-    let _ = stream.write_all(
-        format!(
-            "* The room contains: {:?}\n",
-            clients.lock().unwrap().keys()
-        )
-        .as_bytes(),
+    let line = room_contains(
+        clients
+            .iter()
+            .filter(|(name, client)| name.as_str() != username && client.room == room)
+            .map(|(name, _)| name.as_str()),
     );
-    let _ = stream.flush();
+    reply(clients, username, &line);
+}
+
+/// Adds the user to the default room. Fails if the name is already in use.
+fn add_new_user(username: &str, client: Client, clients: &Clients) -> bool {
+    let mut clients = clients.lock().unwrap();
+
+    if clients.contains_key(username) {
+        let _ = client.outbox.try_send(name_taken(username));
+        return false;
+    }
 
     // Add client to the list
-    clients
-        .lock()
-        .unwrap()
-        .insert(username.clone(), stream.try_clone().unwrap());
+    clients.insert(String::from(username), client);
+    enter_room(username, DEFAULT_ROOM, &mut clients);
+    true
 }
 
-fn read_message(reader: &mut BufReader<TcpStream>) -> Option<String> {
+fn change_room(username: &str, room: &str, clients: &Clients) {
+    let mut clients = clients.lock().unwrap();
+
+    let Some(current) = clients.get(username).map(|c| c.room.clone()) else {
+        return;
+    };
+    if current == room {
+        reply(
+            &mut clients,
+            username,
+            &format!("* You are already in {room}"),
+        );
+        return;
+    }
+
+    announce(&mut clients, &current, username, &left(username));
+
+    if let Some(client) = clients.get_mut(username) {
+        client.room = String::from(room);
+        enter_room(username, room, &mut clients);
+    }
+}
+
+fn list_rooms(username: &str, clients: &Clients) {
+    let mut clients = clients.lock().unwrap();
+
+    let mut rooms: BTreeMap<String, usize> = BTreeMap::new();
+    rooms.insert(String::from(DEFAULT_ROOM), 0);
+    for client in clients.values() {
+        *rooms.entry(client.room.clone()).or_default() += 1;
+    }
+
+    let rooms: Vec<String> = rooms
+        .iter()
+        .map(|(room, users)| format!("{room} ({users})"))
+        .collect();
+    reply(
+        &mut clients,
+        username,
+        &format!("* Rooms: {}", rooms.join(", ")),
+    );
+}
+
+fn list_room_users(username: &str, clients: &Clients) {
+    let mut clients = clients.lock().unwrap();
+    let Some(room) = clients.get(username).map(|c| c.room.clone()) else {
+        return;
+    };
+
+    let mut users: Vec<&str> = clients
+        .iter()
+        .filter(|(_, client)| client.room == room)
+        .map(|(name, _)| name.as_str())
+        .collect();
+    users.sort();
+    let line = format!("* In {}: {}", room, users.join(", "));
+    reply(&mut clients, username, &line);
+}
+
+fn send_private_message(username: &str, to: &str, text: &str, clients: &Clients) {
+    let mut clients = clients.lock().unwrap();
+
+    if clients.contains_key(to) {
+        reply(&mut clients, to, &format!("[{username} -> {to}] {text}"));
+    } else {
+        reply(&mut clients, username, &format!("* No user called {to}"));
+    }
+}
+
+/// Renames `username` to `nick`, returning the name the user now goes by.
+fn rename_user(username: &str, nick: &str, clients: &Clients) -> String {
+    let mut clients = clients.lock().unwrap();
+
+    if clients.contains_key(nick) {
+        reply(&mut clients, username, &name_taken(nick));
+        return String::from(username);
+    }
+    let Some(client) = clients.remove(username) else {
+        return String::from(username);
+    };
+
+    let room = client.room.clone();
+    clients.insert(String::from(nick), client);
+    announce(
+        &mut clients,
+        &room,
+        nick,
+        &format!("* {username} is now known as {nick}"),
+    );
+    reply(
+        &mut clients,
+        nick,
+        &format!("* You are now known as {nick}"),
+    );
+
+    String::from(nick)
+}
+
+async fn read_message(reader: &mut BufReader<OwnedReadHalf>) -> Option<String> {
     let mut message = String::new();
 
-    message.clear();
-    let result = reader.read_line(&mut message);
+    let result = reader.read_line(&mut message).await;
 
     if result.is_err() || result.is_ok_and(|v| v == 0) {
         None
@@ -81,48 +273,42 @@ fn read_message(reader: &mut BufReader<TcpStream>) -> Option<String> {
     }
 }
 
-fn disconnect_user(
-    username: &String,
-    stream: &mut TcpStream,
-    clients: &Arc<Mutex<HashMap<String, TcpStream>>>,
-) {
+fn disconnect_user(username: &str, connected: &mut oneshot::Receiver<()>, clients: &Clients) {
     // Assume the client has closed the connection
-    // Remove the user from the list
-    clients.lock().unwrap().remove(username);
-
-    let _ = stream.shutdown(std::net::Shutdown::Both);
+    // Remove the user from the list, unless they were already dropped for
+    // falling behind and someone else has since taken the name.
+    let mut clients = clients.lock().unwrap();
+    if !matches!(connected.try_recv(), Err(TryRecvError::Empty)) {
+        return;
+    }
+    let client = clients.remove(username);
 
     // Announce user left
-    clients
-        .lock()
-        .unwrap()
-        .values_mut()
-        .for_each(|other_client| {
-            let _ =
-                other_client.write_all(format!("* {} has left the room\n", username).as_bytes());
-            let _ = other_client.flush();
-        });
+    if let Some(client) = client {
+        announce(&mut clients, &client.room, username, &left(username));
+    }
 }
 
-fn broadcast_message(
-    current_user: &String,
-    message: &String,
-    clients: &Arc<Mutex<HashMap<String, TcpStream>>>,
-) {
-    // Sends meesage to all other clients.
-    clients
-        .lock()
-        .unwrap()
-        .iter_mut()
-        .filter(|(k, _)| k.as_str() != current_user)
-        .for_each(|(_, v)| {
-            let _ = v.write_all(format!("[{current_user}] {message}\n").as_bytes());
-            let _ = v.flush();
-        });
+fn broadcast_message(current_user: &str, message: &str, clients: &Clients) {
+    // Sends meesage to all other clients in the same room.
+    let mut clients = clients.lock().unwrap();
+    let Some(room) = clients.get(current_user).map(|c| c.room.clone()) else {
+        return;
+    };
+
+    announce(
+        &mut clients,
+        &room,
+        current_user,
+        &budget_chat_3::message(current_user, message),
+    );
 }
 
-fn handle_client(mut stream: TcpStream, clients: Arc<Mutex<HashMap<String, TcpStream>>>) {
-    let username = match prompt_username(stream.try_clone().unwrap()) {
+async fn handle_client(stream: TcpStream, clients: Clients) {
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+
+    let mut username = match prompt_username(&mut reader, &mut writer).await {
         Some(name) => name,
         None => {
             println!("Invalid username, disconnecting");
@@ -130,34 +316,306 @@ fn handle_client(mut stream: TcpStream, clients: Arc<Mutex<HashMap<String, TcpSt
         }
     };
 
+    // Lines for this client are queued and written from their own task, so a
+    // slow reader never blocks whoever is sending to them.
+    let (outbox, mut pending) = mpsc::channel::<String>(QUEUE_LENGTH);
+    let writing = tokio::spawn(async move {
+        while let Some(line) = pending.recv().await {
+            if writer
+                .write_all(format!("{line}\n").as_bytes())
+                .await
+                .is_err()
+            {
+                break;
+            }
+        }
+        let _ = writer.shutdown().await;
+    });
+
     // Add the new user.
-    add_new_user(&username, &mut stream, &clients);
+    let (connected, mut disconnected) = oneshot::channel();
+    let client = Client {
+        room: String::from(DEFAULT_ROOM),
+        outbox,
+        _connected: connected,
+    };
+    if !add_new_user(&username, client, &clients) {
+        println!("Duplicate username, disconnecting");
+        let _ = writing.await;
+        return;
+    }
 
     // Message loop
-    let mut reader = BufReader::new(stream.try_clone().unwrap());
     loop {
-        let message = match read_message(&mut reader) {
+        let message = tokio::select! {
+            biased;
+            // Dropped from the chat for falling behind.
+            _ = &mut disconnected => break,
+            message = read_message(&mut reader) => message,
+        };
+        let message = match message {
             Some(msg) => String::from(msg.trim()),
             None => {
-                disconnect_user(&username, &mut stream, &clients);
-                return;
+                disconnect_user(&username, &mut disconnected, &clients);
+                break;
             }
         };
 
-        broadcast_message(&username, &message, &clients);
+        match parse_command(&message) {
+            Ok(Command::Join(room)) => change_room(&username, &room, &clients),
+            Ok(Command::Leave) => change_room(&username, DEFAULT_ROOM, &clients),
+            Ok(Command::Rooms) => list_rooms(&username, &clients),
+            Ok(Command::Who) => list_room_users(&username, &clients),
+            Ok(Command::Msg(to, text)) => send_private_message(&username, &to, &text, &clients),
+            Ok(Command::Nick(nick)) => username = rename_user(&username, &nick, &clients),
+            Ok(Command::Message(message)) => broadcast_message(&username, &message, &clients),
+            Err(e) => reply(&mut clients.lock().unwrap(), &username, &format!("* {e}")),
+        }
     }
+
+    // The client may never read what is left, so don't wait for it.
+    writing.abort();
 }
 
-fn main() {
-    let listener = TcpListener::bind("0.0.0.0:10000").unwrap();
-    let clients: Arc<Mutex<HashMap<String, TcpStream>>> = Arc::new(Mutex::new(HashMap::new()));
+async fn serve(listener: TcpListener) {
+    let clients: Clients = Arc::new(Mutex::new(HashMap::new()));
 
-    for stream in listener.incoming() {
-        let stream = stream.unwrap();
+    loop {
+        let (stream, _) = listener.accept().await.unwrap();
         let clients = clients.clone();
 
-        thread::spawn(|| {
-            handle_client(stream, clients);
+        tokio::spawn(handle_client(stream, clients));
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let listener = TcpListener::bind("0.0.0.0:10000").await.unwrap();
+    serve(listener).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io::{BufRead, BufReader, Write},
+        net::TcpStream,
+        sync::atomic::{AtomicBool, Ordering},
+        thread,
+    };
+
+    #[test]
+    fn commands() {
+        assert_eq!(
+            parse_command("hello /join"),
+            Ok(Command::Message(String::from("hello /join")))
+        );
+        assert_eq!(
+            parse_command("/join ops"),
+            Ok(Command::Join(String::from("ops")))
+        );
+        assert_eq!(parse_command("/leave"), Ok(Command::Leave));
+        assert_eq!(parse_command("/rooms"), Ok(Command::Rooms));
+        assert_eq!(parse_command("/who"), Ok(Command::Who));
+
+        assert!(parse_command("/join").is_err());
+        assert!(parse_command("/join two rooms").is_err());
+        assert!(parse_command("/join #ops").is_err());
+        assert!(parse_command("/leave now").is_err());
+        assert_eq!(
+            parse_command("/shrug"),
+            Ok(Command::Message(String::from("/shrug")))
+        );
+        assert_eq!(
+            parse_command("/path/to/x is missing"),
+            Ok(Command::Message(String::from("/path/to/x is missing")))
+        );
+
+        assert_eq!(
+            parse_command("/msg bob  are you on call?"),
+            Ok(Command::Msg(
+                String::from("bob"),
+                String::from("are you on call?")
+            ))
+        );
+        assert_eq!(
+            parse_command("/nick alice2"),
+            Ok(Command::Nick(String::from("alice2")))
+        );
+        assert!(parse_command("/msg bob").is_err());
+        assert!(parse_command("/msg b-o-b hi").is_err());
+        assert!(parse_command("/nick").is_err());
+        assert!(parse_command("/nick alice smith").is_err());
+    }
+
+    struct TestClient {
+        reader: BufReader<TcpStream>,
+        stream: TcpStream,
+    }
+
+    impl TestClient {
+        /// Connects and answers the name prompt.
+        fn join(addr: std::net::SocketAddr, name: &str) -> TestClient {
+            let stream = TcpStream::connect(addr).unwrap();
+            stream
+                .set_read_timeout(Some(std::time::Duration::from_secs(5)))
+                .unwrap();
+            let mut client = TestClient {
+                reader: BufReader::new(stream.try_clone().unwrap()),
+                stream,
+            };
+
+            assert_eq!(
+                client.read(),
+                "Welcome to budgetchat! What shall I call you?"
+            );
+            client.send(name);
+            client
+        }
+
+        fn send(&mut self, line: &str) {
+            self.stream
+                .write_all(format!("{line}\n").as_bytes())
+                .unwrap();
+        }
+
+        fn read(&mut self) -> String {
+            let mut line = String::new();
+            self.reader.read_line(&mut line).unwrap();
+            String::from(line.trim_end_matches('\n'))
+        }
+
+        fn is_closed(&mut self) -> bool {
+            let mut line = String::new();
+            matches!(self.reader.read_line(&mut line), Ok(0))
+        }
+    }
+
+    fn start_server() -> std::net::SocketAddr {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        listener.set_nonblocking(true).unwrap();
+        thread::spawn(move || {
+            let rt = tokio::runtime::Runtime::new().unwrap();
+            rt.block_on(async { serve(TcpListener::from_std(listener).unwrap()).await });
         });
+        addr
+    }
+
+    #[test]
+    fn presence_and_messages() {
+        let addr = start_server();
+
+        let mut alice = TestClient::join(addr, "alice");
+        assert_eq!(alice.read(), "* The room contains: ");
+
+        let mut bob = TestClient::join(addr, "bob");
+        assert_eq!(bob.read(), "* The room contains: alice");
+        assert_eq!(alice.read(), "* bob has entered the room");
+
+        let mut carol = TestClient::join(addr, "carol");
+        assert_eq!(carol.read(), "* The room contains: alice, bob");
+        assert_eq!(alice.read(), "* carol has entered the room");
+        assert_eq!(bob.read(), "* carol has entered the room");
+
+        bob.send("hi all");
+        assert_eq!(alice.read(), "[bob] hi all");
+        assert_eq!(carol.read(), "[bob] hi all");
+
+        // Lines that only look like commands are ordinary messages.
+        carol.send("/shrug");
+        assert_eq!(alice.read(), "[carol] /shrug");
+        assert_eq!(bob.read(), "[carol] /shrug");
+
+        drop(bob);
+        assert_eq!(alice.read(), "* bob has left the room");
+        assert_eq!(carol.read(), "* bob has left the room");
+    }
+
+    #[test]
+    fn rejects_duplicate_names() {
+        let addr = start_server();
+
+        let mut alice = TestClient::join(addr, "alice");
+        assert_eq!(alice.read(), "* The room contains: ");
+
+        let mut impostor = TestClient::join(addr, "alice");
+        assert_eq!(impostor.read(), "* The name alice is taken");
+        assert!(impostor.is_closed());
+
+        // The first alice keeps her connection and nobody saw the impostor.
+        let mut bob = TestClient::join(addr, "bob");
+        assert_eq!(bob.read(), "* The room contains: alice");
+        assert_eq!(alice.read(), "* bob has entered the room");
+        alice.send("still here");
+        assert_eq!(bob.read(), "[alice] still here");
+    }
+
+    #[test]
+    fn rooms_and_private_messages() {
+        let addr = start_server();
+
+        let mut alice = TestClient::join(addr, "alice");
+        alice.read();
+        let mut bob = TestClient::join(addr, "bob");
+        bob.read();
+        alice.read();
+
+        bob.send("/join ops");
+        assert_eq!(bob.read(), "* The room contains: ");
+        assert_eq!(alice.read(), "* bob has left the room");
+
+        alice.send("/msg bob ping");
+        assert_eq!(bob.read(), "[alice -> bob] ping");
+
+        bob.send("/nick bobby");
+        assert_eq!(bob.read(), "* You are now known as bobby");
+        bob.send("/who");
+        assert_eq!(bob.read(), "* In ops: bobby");
+        bob.send("/leave");
+        assert_eq!(bob.read(), "* The room contains: alice");
+        assert_eq!(alice.read(), "* bobby has entered the room");
+    }
+
+    #[test]
+    fn disconnects_slow_readers() {
+        let addr = start_server();
+
+        let mut sloth = TestClient::join(addr, "sloth");
+        assert_eq!(sloth.read(), "* The room contains: ");
+        let mut chatty = TestClient::join(addr, "chatty");
+        assert_eq!(chatty.read(), "* The room contains: sloth");
+
+        // sloth stops reading while chatty floods the room.
+        let done = Arc::new(AtomicBool::new(false));
+        let mut flood = chatty.stream.try_clone().unwrap();
+        let flooding = done.clone();
+        let flooder = thread::spawn(move || {
+            let line = format!("{}\n", "x".repeat(1000));
+            while !flooding.load(Ordering::Relaxed) {
+                if flood.write_all(line.as_bytes()).is_err() {
+                    break;
+                }
+            }
+        });
+
+        assert_eq!(chatty.read(), "* sloth has left the room");
+        done.store(true, Ordering::Relaxed);
+        flooder.join().unwrap();
+
+        // Wait for the server to get through the flood, a newcomer would
+        // otherwise be swamped by it too.
+        chatty.send("/who");
+        assert_eq!(chatty.read(), "* In lobby: chatty");
+
+        // The room carries on without sloth...
+        let mut carol = TestClient::join(addr, "carol");
+        assert_eq!(carol.read(), "* The room contains: chatty");
+
+        // ...and sloth's connection is closed once the backlog is read.
+        let mut line = String::new();
+        while sloth.reader.read_line(&mut line).unwrap() > 0 {
+            line.clear();
+        }
     }
 }
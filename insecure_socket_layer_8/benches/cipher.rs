@@ -0,0 +1,70 @@
+use criterion::{Criterion, Throughput, criterion_group, criterion_main};
+use insecure_socket_layer_8::isl::cipher::Cipher;
+use std::hint::black_box;
+use tokio::io::BufReader;
+use tokio::runtime::Runtime;
+
+/// Roughly 4 MB of toy list requests.
+fn toy_lists() -> Vec<u8> {
+    let mut toys = Vec::new();
+    let mut n = 0u32;
+    while toys.len() < 4 * 1024 * 1024 {
+        for toy in ["dog", "car", "rat", "cat", "spinning top", "jigsaw puzzle"] {
+            toys.extend_from_slice(format!("{}x {toy},", n % 1000).as_bytes());
+            n += 1;
+        }
+        toys.pop();
+        toys.push(b'\n');
+    }
+    toys
+}
+
+fn cipher(spec: &[u8]) -> Cipher {
+    let rt = Runtime::new().unwrap();
+    rt.block_on(Cipher::new(&mut BufReader::new(spec))).unwrap()
+}
+
+fn bench_cipher(c: &mut Criterion) {
+    let toys = toy_lists();
+    let mut group = c.benchmark_group("cipher");
+    group.throughput(Throughput::Bytes(toys.len() as u64));
+
+    for (name, spec) in [
+        ("xor(1),reversebits", &[0x02, 0x01, 0x01, 0x00][..]),
+        (
+            "xor(123),addpos,reversebits",
+            &[0x02, 0x7b, 0x05, 0x01, 0x00][..],
+        ),
+    ] {
+        let cipher = cipher(spec);
+        let encoded: Vec<u8> = toys
+            .iter()
+            .enumerate()
+            .map(|(pos, byte)| cipher.encode(*byte, pos))
+            .collect();
+
+        group.bench_function(format!("encode {name}"), |b| {
+            b.iter(|| {
+                black_box(&toys)
+                    .iter()
+                    .enumerate()
+                    .map(|(pos, byte)| cipher.encode(*byte, pos))
+                    .collect::<Vec<u8>>()
+            })
+        });
+        group.bench_function(format!("decode {name}"), |b| {
+            b.iter(|| {
+                black_box(&encoded)
+                    .iter()
+                    .enumerate()
+                    .map(|(pos, byte)| cipher.decode(*byte, pos))
+                    .collect::<Vec<u8>>()
+            })
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_cipher);
+criterion_main!(benches);
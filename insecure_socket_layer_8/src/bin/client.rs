@@ -0,0 +1,94 @@
+//! Connects to an insecure sockets layer server and sends toy requests typed
+//! on stdin, one per line, printing each response.
+
+use insecure_socket_layer_8::isl::{Cipher, InsecureSocketLayer};
+use std::process::ExitCode;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::net::TcpStream;
+
+const USAGE: &str = "Usage: client [--addr HOST:PORT] [--cipher SPEC]\n\
+Example: client --addr 127.0.0.1:10000 --cipher \"xor(123),addpos,reversebits\"";
+
+struct Config {
+    addr: String,
+    cipher: Cipher,
+}
+
+fn parse<T: std::str::FromStr>(flag: &str, value: Option<String>) -> Result<T, String> {
+    value
+        .ok_or(format!("Missing value for {flag}"))?
+        .parse()
+        .map_err(|_| format!("Invalid value for {flag}"))
+}
+
+impl Config {
+    fn from_args(mut args: impl Iterator<Item = String>) -> Result<Config, String> {
+        let mut config = Config {
+            addr: String::from("127.0.0.1:10000"),
+            cipher: Cipher::builder().xor(123).addpos().reverse_bits().build(),
+        };
+
+        while let Some(flag) = args.next() {
+            let value = args.next();
+            match flag.as_str() {
+                "--addr" => config.addr = parse(&flag, value)?,
+                "--cipher" => config.cipher = parse(&flag, value)?,
+                _ => return Err(format!("Unknown flag {flag}")),
+            }
+        }
+
+        if config.cipher.is_no_op() {
+            return Err(format!("Cipher {} is no op", config.cipher));
+        }
+
+        Ok(config)
+    }
+}
+
+async fn run(config: Config) -> Result<(), String> {
+    let stream = TcpStream::connect(&config.addr)
+        .await
+        .map_err(|e| format!("Failed to connect to {}: {e}", config.addr))?;
+
+    println!("Connected to {} with cipher {}", config.addr, config.cipher);
+    let mut isl = InsecureSocketLayer::connect(stream, config.cipher).await?;
+
+    let mut lines = BufReader::new(tokio::io::stdin()).lines();
+    while let Some(line) = lines
+        .next_line()
+        .await
+        .map_err(|_| "Failed to read stdin")?
+    {
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        isl.write(format!("{line}\n")).await?;
+        let response = isl
+            .read()
+            .await
+            .map_err(|_| "Server closed the connection")?;
+        print!("{response}");
+    }
+
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let config = match Config::from_args(std::env::args().skip(1)) {
+        Ok(config) => config,
+        Err(e) => {
+            println!("{e}\n{USAGE}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    match run(config).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            println!("{e}");
+            ExitCode::FAILURE
+        }
+    }
+}
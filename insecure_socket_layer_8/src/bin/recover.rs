@@ -0,0 +1,110 @@
+//! Lists the ciphers that could have produced a captured ISL stream, given
+//! known plaintext or, by default, the shape of toy requests.
+
+use insecure_socket_layer_8::recover::{Plaintext, recover};
+use std::process::ExitCode;
+
+const USAGE: &str = "Usage: recover (--file PATH | --hex HEX) [--plaintext TEXT] \
+[--offset N] [--max-ops N]\n\
+The capture holds the obfuscated bytes after the cipher spec. Without \
+--plaintext it is expected to decode to toy requests.";
+
+struct Config {
+    capture: Vec<u8>,
+    plaintext: Plaintext,
+    offset: usize,
+    max_ops: usize,
+}
+
+fn parse<T: std::str::FromStr>(flag: &str, value: Option<String>) -> Result<T, String> {
+    value
+        .ok_or(format!("Missing value for {flag}"))?
+        .parse()
+        .map_err(|_| format!("Invalid value for {flag}"))
+}
+
+fn parse_hex(hex: &str) -> Result<Vec<u8>, String> {
+    let digits: Vec<char> = hex.chars().filter(|c| !c.is_ascii_whitespace()).collect();
+    if !digits.len().is_multiple_of(2) {
+        return Err(String::from("Odd number of hex digits"));
+    }
+
+    digits
+        .chunks(2)
+        .map(|pair| {
+            let pair: String = pair.iter().collect();
+            u8::from_str_radix(&pair, 16).map_err(|_| format!("Invalid hex byte {pair}"))
+        })
+        .collect()
+}
+
+impl Config {
+    fn from_args(mut args: impl Iterator<Item = String>) -> Result<Config, String> {
+        let mut config = Config {
+            capture: Vec::new(),
+            plaintext: Plaintext::ToyRequests,
+            offset: 0,
+            max_ops: 3,
+        };
+
+        while let Some(flag) = args.next() {
+            let value = args.next();
+            match flag.as_str() {
+                "--file" => {
+                    let path: String = parse(&flag, value)?;
+                    config.capture =
+                        std::fs::read(&path).map_err(|e| format!("Failed to read {path}: {e}"))?;
+                }
+                "--hex" => config.capture = parse_hex(&parse::<String>(&flag, value)?)?,
+                "--plaintext" => {
+                    let text: String = parse(&flag, value)?;
+                    config.plaintext = Plaintext::Exact(text.replace("\\n", "\n").into_bytes());
+                }
+                "--offset" => config.offset = parse(&flag, value)?,
+                "--max-ops" => config.max_ops = parse(&flag, value)?,
+                _ => return Err(format!("Unknown flag {flag}")),
+            }
+        }
+
+        if config.capture.is_empty() {
+            return Err(String::from("Need a non-empty capture"));
+        }
+
+        Ok(config)
+    }
+}
+
+fn main() -> ExitCode {
+    let config = match Config::from_args(std::env::args().skip(1)) {
+        Ok(config) => config,
+        Err(e) => {
+            println!("{e}\n{USAGE}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let found = recover(
+        &config.capture,
+        config.offset,
+        &config.plaintext,
+        config.max_ops,
+    );
+
+    for cipher in &found {
+        let decoded: Vec<u8> = config
+            .capture
+            .iter()
+            .enumerate()
+            .map(|(i, byte)| cipher.decode(*byte, config.offset + i))
+            .take_while(|byte| *byte != b'\n')
+            .collect();
+        println!("{cipher}: {}", String::from_utf8_lossy(&decoded));
+    }
+    println!("{} consistent ciphers", found.len());
+
+    if found.is_empty() {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
+}
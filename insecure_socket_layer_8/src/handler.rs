@@ -0,0 +1,102 @@
+//! Applications that can run behind the Insecure Socket Layer.
+//!
+//! A server creates one handler per connection and feeds it decoded request
+//! lines. Handlers answer through an [`Outbox`], which also lets them push
+//! lines the client never asked for, like chat messages from other users.
+//! Answers wait for room in the outbox, so a client pipelining requests is
+//! slowed down. Pushed lines can't wait on the client, so a client too far
+//! behind to take one is disconnected instead.
+
+mod budget_chat;
+mod echo;
+mod prime_time;
+mod toys;
+
+pub use budget_chat::{BudgetChat, BudgetChatClient};
+pub use echo::Echo;
+pub use prime_time::PrimeTime;
+pub use toys::Toys;
+
+use std::future::Future;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use tokio::sync::{Notify, mpsc};
+
+/// Lines queued for a client before answers wait and pushes disconnect it.
+pub const OUTBOX_LENGTH: usize = 128;
+
+/// Lines queued here are encoded and sent to the client in order.
+///
+/// The queue is bounded so a client that never reads can't make the server
+/// buffer without limit.
+#[derive(Clone)]
+pub struct Outbox {
+    lines: mpsc::Sender<String>,
+    failed: Arc<Notify>,
+    overflowed: Arc<AtomicBool>,
+}
+
+impl Outbox {
+    /// A new outbox and the receiver its lines come out of.
+    pub fn new() -> (Outbox, mpsc::Receiver<String>) {
+        let (lines, pending) = mpsc::channel(OUTBOX_LENGTH);
+        let outbox = Outbox {
+            lines,
+            failed: Arc::new(Notify::new()),
+            overflowed: Arc::new(AtomicBool::new(false)),
+        };
+        (outbox, pending)
+    }
+
+    /// Queues an answer, waiting for room if the client is behind.
+    pub async fn send(&self, line: String) -> Result<(), String> {
+        self.lines
+            .send(line)
+            .await
+            .map_err(|_| String::from("Client disconnected"))
+    }
+
+    /// Queues a line the client didn't ask for. Fails if the client is too far
+    /// behind or gone, in which case [`Outbox::failed`] resolves and the
+    /// connection should be closed.
+    pub fn push(&self, line: String) -> Result<(), String> {
+        self.lines.try_send(line).map_err(|_| {
+            self.overflowed.store(true, Ordering::Relaxed);
+            self.failed.notify_one();
+            String::from("Client is not reading responses")
+        })
+    }
+
+    /// Whether the queue is full.
+    pub fn is_full(&self) -> bool {
+        self.lines.capacity() == 0
+    }
+
+    /// Whether a pushed line could not be queued.
+    pub fn has_failed(&self) -> bool {
+        self.overflowed.load(Ordering::Relaxed)
+    }
+
+    /// Resolves once a pushed line could not be queued.
+    pub async fn failed(&self) {
+        self.failed.notified().await
+    }
+}
+
+pub trait LineHandler: Send + 'static {
+    /// Called once the cipher is agreed, before any request.
+    fn connected(&mut self, _out: &Outbox) -> impl Future<Output = Result<(), String>> + Send {
+        async { Ok(()) }
+    }
+
+    /// Handles one request line, newline included. An error closes the
+    /// connection once the queued lines are sent.
+    fn line(
+        &mut self,
+        line: String,
+        out: &Outbox,
+    ) -> impl Future<Output = Result<(), String>> + Send;
+
+    /// Called when the connection ends, however it ends.
+    fn disconnected(&mut self) {}
+}
@@ -0,0 +1,250 @@
+use super::{LineHandler, Outbox};
+use budget_chat_3 as chat;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+struct Member {
+    name: String,
+    out: Outbox,
+}
+
+#[derive(Default)]
+struct Room {
+    members: HashMap<u64, Member>,
+    next_id: u64,
+}
+
+impl Room {
+    /// Sends `line` to everyone but `from`. Members too far behind to take it
+    /// are dropped from the room, which also disconnects them.
+    fn broadcast(&mut self, from: u64, line: &str) {
+        let ids: Vec<u64> = self
+            .members
+            .keys()
+            .copied()
+            .filter(|id| *id != from)
+            .collect();
+
+        for id in ids {
+            let Some(member) = self.members.get(&id) else {
+                continue;
+            };
+            if member.out.push(format!("{line}\n")).is_ok() {
+                continue;
+            }
+
+            if let Some(member) = self.members.remove(&id) {
+                self.broadcast(id, &chat::left(&member.name));
+            }
+        }
+    }
+}
+
+/// A single chat room shared by every connection made from it.
+#[derive(Clone, Default)]
+pub struct BudgetChat {
+    room: Arc<Mutex<Room>>,
+}
+
+impl BudgetChat {
+    pub fn new() -> BudgetChat {
+        BudgetChat::default()
+    }
+
+    /// Handler for a new connection to the room.
+    pub fn client(&self) -> BudgetChatClient {
+        BudgetChatClient {
+            room: self.room.clone(),
+            joined: None,
+        }
+    }
+}
+
+/// One connection to a [`BudgetChat`] room. The first line is the user name,
+/// every line after that a message to the others.
+pub struct BudgetChatClient {
+    room: Arc<Mutex<Room>>,
+    /// Member id and name once the user picked a name.
+    joined: Option<(u64, String)>,
+}
+
+impl LineHandler for BudgetChatClient {
+    async fn connected(&mut self, out: &Outbox) -> Result<(), String> {
+        out.send(format!("{}\n", chat::WELCOME)).await
+    }
+
+    async fn line(&mut self, line: String, out: &Outbox) -> Result<(), String> {
+        let line = line.trim_end_matches(['\r', '\n']);
+        let mut room = self.room.lock().map_err(|_| "Chat room poisoned")?;
+
+        match &self.joined {
+            Some((id, _)) if !room.members.contains_key(id) => {
+                Err(String::from("Dropped from the room for falling behind"))
+            }
+            Some((id, name)) => {
+                room.broadcast(*id, &chat::message(name, line));
+                Ok(())
+            }
+            None => {
+                if !chat::is_valid_name(line) {
+                    return Err(String::from("Invalid username"));
+                }
+                if room.members.values().any(|m| m.name == line) {
+                    out.push(format!("{}\n", chat::name_taken(line)))?;
+                    return Err(String::from("Duplicate username"));
+                }
+
+                let present = chat::room_contains(room.members.values().map(|m| m.name.as_str()));
+                out.push(format!("{present}\n"))?;
+
+                let id = room.next_id;
+                room.next_id += 1;
+                room.broadcast(id, &chat::entered(line));
+                room.members.insert(
+                    id,
+                    Member {
+                        name: String::from(line),
+                        out: out.clone(),
+                    },
+                );
+                self.joined = Some((id, String::from(line)));
+                Ok(())
+            }
+        }
+    }
+
+    fn disconnected(&mut self) {
+        if let Some((id, name)) = self.joined.take()
+            && let Ok(mut room) = self.room.lock()
+            && room.members.remove(&id).is_some()
+        {
+            room.broadcast(id, &chat::left(&name));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::handler::OUTBOX_LENGTH;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn chat_session() {
+        Runtime::new().unwrap().block_on(async {
+            let chat = BudgetChat::new();
+            let (alice_out, mut alice) = Outbox::new();
+            let (bob_out, mut bob) = Outbox::new();
+
+            let mut alice_client = chat.client();
+            alice_client.connected(&alice_out).await.unwrap();
+            assert_eq!(
+                alice.try_recv().unwrap(),
+                "Welcome to budgetchat! What shall I call you?\n"
+            );
+            alice_client
+                .line(String::from("alice\n"), &alice_out)
+                .await
+                .unwrap();
+            assert_eq!(alice.try_recv().unwrap(), "* The room contains: \n");
+
+            let mut bob_client = chat.client();
+            bob_client.connected(&bob_out).await.unwrap();
+            bob.try_recv().unwrap();
+            bob_client
+                .line(String::from("bob\n"), &bob_out)
+                .await
+                .unwrap();
+            assert_eq!(bob.try_recv().unwrap(), "* The room contains: alice\n");
+            assert_eq!(alice.try_recv().unwrap(), "* bob has entered the room\n");
+
+            bob_client
+                .line(String::from("hi alice\n"), &bob_out)
+                .await
+                .unwrap();
+            assert_eq!(alice.try_recv().unwrap(), "[bob] hi alice\n");
+            assert!(bob.try_recv().is_err());
+
+            bob_client.disconnected();
+            assert_eq!(alice.try_recv().unwrap(), "* bob has left the room\n");
+
+            let mut eve_client = chat.client();
+            assert!(
+                eve_client
+                    .line(String::from("e v e\n"), &bob_out)
+                    .await
+                    .is_err()
+            );
+            assert!(alice.try_recv().is_err());
+        });
+    }
+
+    #[test]
+    fn rejects_taken_names() {
+        Runtime::new().unwrap().block_on(async {
+            let chat = BudgetChat::new();
+            let (alice_out, mut alice) = Outbox::new();
+            let (impostor_out, mut impostor) = Outbox::new();
+
+            chat.client()
+                .line(String::from("alice\n"), &alice_out)
+                .await
+                .unwrap();
+            alice.try_recv().unwrap();
+
+            let mut impostor_client = chat.client();
+            assert!(
+                impostor_client
+                    .line(String::from("alice\n"), &impostor_out)
+                    .await
+                    .is_err()
+            );
+            assert_eq!(impostor.try_recv().unwrap(), "* The name alice is taken\n");
+
+            // Nobody saw the impostor come or go.
+            impostor_client.disconnected();
+            assert!(alice.try_recv().is_err());
+        });
+    }
+
+    #[test]
+    fn drops_members_who_fall_behind() {
+        Runtime::new().unwrap().block_on(async {
+            let chat = BudgetChat::new();
+            let (alice_out, mut alice) = Outbox::new();
+            let (sloth_out, _sloth) = Outbox::new();
+
+            let mut sloth_client = chat.client();
+            sloth_client
+                .line(String::from("sloth\n"), &sloth_out)
+                .await
+                .unwrap();
+            let mut alice_client = chat.client();
+            alice_client
+                .line(String::from("alice\n"), &alice_out)
+                .await
+                .unwrap();
+            assert_eq!(alice.try_recv().unwrap(), "* The room contains: sloth\n");
+
+            // sloth never reads, so its outbox fills up.
+            for _ in 0..OUTBOX_LENGTH {
+                alice_client
+                    .line(String::from("hello\n"), &alice_out)
+                    .await
+                    .unwrap();
+            }
+            assert!(sloth_out.is_full());
+            assert_eq!(alice.try_recv().unwrap(), "* sloth has left the room\n");
+
+            // Its connection is closed on its next line, and it isn't announced twice.
+            assert!(
+                sloth_client
+                    .line(String::from("hi\n"), &sloth_out)
+                    .await
+                    .is_err()
+            );
+            sloth_client.disconnected();
+            assert!(alice.try_recv().is_err());
+        });
+    }
+}
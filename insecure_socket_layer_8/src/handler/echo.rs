@@ -0,0 +1,30 @@
+use super::{LineHandler, Outbox};
+
+/// Sends every line straight back.
+pub struct Echo;
+
+impl LineHandler for Echo {
+    async fn line(&mut self, line: String, out: &Outbox) -> Result<(), String> {
+        out.send(line).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn echoes_lines() {
+        Runtime::new().unwrap().block_on(async {
+            let (out, mut sent) = Outbox::new();
+            let mut echo = Echo;
+
+            echo.line(String::from("hello\n"), &out).await.unwrap();
+            echo.line(String::from("4x dog\n"), &out).await.unwrap();
+
+            assert_eq!(sent.try_recv().unwrap(), "hello\n");
+            assert_eq!(sent.try_recv().unwrap(), "4x dog\n");
+        });
+    }
+}
@@ -0,0 +1,95 @@
+use super::{LineHandler, Outbox};
+use prime_time_1::is_prime;
+use serde::{Deserialize, Serialize};
+
+#[derive(Deserialize)]
+struct Request {
+    method: String,
+    number: f64,
+}
+
+#[derive(Serialize)]
+struct Response {
+    method: String,
+    prime: bool,
+}
+
+/// Answers `{"method":"isPrime","number":N}` requests. Anything else gets a
+/// malformed response and closes the connection.
+pub struct PrimeTime;
+
+async fn respond(out: &Outbox, method: &str, prime: bool) -> Result<(), String> {
+    let response = Response {
+        method: String::from(method),
+        prime,
+    };
+    let mut response = serde_json::to_string(&response).map_err(|e| e.to_string())?;
+    response.push('\n');
+    out.send(response).await
+}
+
+impl LineHandler for PrimeTime {
+    async fn line(&mut self, line: String, out: &Outbox) -> Result<(), String> {
+        let request = match serde_json::from_str::<Request>(&line) {
+            Ok(request) if request.method == "isPrime" => request,
+            _ => {
+                respond(out, "malformed", false).await?;
+                return Err(String::from("Malformed request"));
+            }
+        };
+
+        // Non-integers are never prime.
+        let number = request.number;
+        let prime = number.fract() == 0.0 && number <= u64::MAX as f64 && is_prime(number as u64);
+        respond(out, "isPrime", prime).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn answers_requests() {
+        Runtime::new().unwrap().block_on(async {
+            let (out, mut sent) = Outbox::new();
+            let mut prime_time = PrimeTime;
+
+            for (request, response) in [
+                (
+                    r#"{"method":"isPrime","number":7}"#,
+                    r#"{"method":"isPrime","prime":true}"#,
+                ),
+                (
+                    r#"{"method":"isPrime","number":8}"#,
+                    r#"{"method":"isPrime","prime":false}"#,
+                ),
+                (
+                    r#"{"method":"isPrime","number":7.5}"#,
+                    r#"{"method":"isPrime","prime":false}"#,
+                ),
+                (
+                    r#"{"method":"isPrime","number":-7}"#,
+                    r#"{"method":"isPrime","prime":false}"#,
+                ),
+            ] {
+                prime_time.line(format!("{request}\n"), &out).await.unwrap();
+                assert_eq!(sent.try_recv().unwrap(), format!("{response}\n"));
+            }
+
+            for request in [
+                r#"{"method":"isPrime"}"#,
+                r#"{"method":"isComposite","number":7}"#,
+                r#"{"method":"isPrime","number":"7"}"#,
+                "hello",
+            ] {
+                assert!(prime_time.line(format!("{request}\n"), &out).await.is_err());
+                assert_eq!(
+                    sent.try_recv().unwrap(),
+                    "{\"method\":\"malformed\",\"prime\":false}\n"
+                );
+            }
+        });
+    }
+}
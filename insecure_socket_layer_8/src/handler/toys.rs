@@ -0,0 +1,11 @@
+use super::{LineHandler, Outbox};
+use crate::toys::get_most_toys;
+
+/// Answers each toy list with the toy there are most copies of.
+pub struct Toys;
+
+impl LineHandler for Toys {
+    async fn line(&mut self, line: String, out: &Outbox) -> Result<(), String> {
+        out.send(get_most_toys(line)?).await
+    }
+}
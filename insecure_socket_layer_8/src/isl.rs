@@ -1,14 +1,54 @@
-mod cipher;
+pub mod cipher;
 
-use cipher::Cipher;
-use tokio::io::{AsyncRead, AsyncWrite};
-use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader, BufWriter};
+pub use cipher::{Cipher, CipherBuilder, Operation};
+use std::io;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, ready};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncWrite, ReadBuf};
+use tokio::io::{AsyncWriteExt, BufReader, ReadHalf, WriteHalf};
+
+/// Most bytes encoded by a single `poll_write`.
+const MAX_WRITE_CHUNK: usize = 8192;
+
+/// Longest line, newline included, that `read` and `read_line` accept unless
+/// changed with `set_max_line_length`.
+pub const DEFAULT_MAX_LINE_LENGTH: usize = 64 * 1024;
 
 pub struct InsecureSocketLayer<S> {
     reader: BufReader<S>,
-    cipher: Cipher,
-    client_pos: usize,
-    server_pos: usize,
+    decoder: Decoder,
+    encoder: Encoder,
+}
+
+/// Reading half of an [`InsecureSocketLayer`], see
+/// [`InsecureSocketLayer::into_split`].
+pub struct IslReadHalf<S> {
+    reader: BufReader<ReadHalf<S>>,
+    decoder: Decoder,
+}
+
+/// Writing half of an [`InsecureSocketLayer`], see
+/// [`InsecureSocketLayer::into_split`].
+pub struct IslWriteHalf<S> {
+    writer: WriteHalf<S>,
+    encoder: Encoder,
+}
+
+/// Decodes the incoming direction of a stream, client-to-server on the
+/// server side and server-to-client on the client side.
+struct Decoder {
+    cipher: Arc<Cipher>,
+    pos: usize,
+    max_line_length: usize,
+    decoded: Vec<u8>,
+    consumed: usize,
+}
+
+/// Encodes the outgoing direction of a stream.
+struct Encoder {
+    cipher: Arc<Cipher>,
+    pos: usize,
 }
 
 impl<S> InsecureSocketLayer<S>
@@ -23,62 +63,357 @@ where
             return Err(String::from("Cipher is no op"));
         }
 
-        Ok(InsecureSocketLayer {
+        Ok(InsecureSocketLayer::with_cipher(reader, cipher))
+    }
+
+    /// Client side of the layer: sends the cipher spec to the server, then
+    /// encodes requests and decodes responses with it.
+    pub async fn connect(stream: S, cipher: Cipher) -> Result<InsecureSocketLayer<S>, String> {
+        if cipher.is_no_op() {
+            return Err(String::from("Cipher is no op"));
+        }
+
+        let mut reader = BufReader::new(stream);
+        reader
+            .write_all(&cipher.to_bytes())
+            .await
+            .map_err(|_| "Failed to write cipher spec")?;
+        reader.flush().await.map_err(|_| "Failed to flush writer")?;
+
+        Ok(InsecureSocketLayer::with_cipher(reader, cipher))
+    }
+
+    fn with_cipher(reader: BufReader<S>, cipher: Cipher) -> InsecureSocketLayer<S> {
+        let cipher = Arc::new(cipher);
+        InsecureSocketLayer {
             reader,
-            cipher,
-            client_pos: 0,
-            server_pos: 0,
-        })
+            decoder: Decoder::new(cipher.clone()),
+            encoder: Encoder::new(cipher),
+        }
     }
 
+    /// Reads one line, newline included, and checks it is valid UTF-8.
     pub async fn read(&mut self) -> Result<String, String> {
-        let mut decoded_request = String::new();
+        request_string(self.read_line().await?)
+    }
 
-        loop {
-            let byte = self
-                .reader
-                .read_u8()
-                .await
-                .map_err(|_| "Failed to read request byte")?;
+    /// Reads one line, newline included, as raw decoded bytes.
+    pub async fn read_line(&mut self) -> Result<Vec<u8>, String> {
+        let max_line_length = self.decoder.max_line_length;
+        read_line(self, max_line_length).await
+    }
+
+    /// Lines longer than `max` bytes, newline included, make `read` and
+    /// `read_line` fail instead of buffering without bound.
+    pub fn set_max_line_length(&mut self, max: usize) {
+        self.decoder.max_line_length = max;
+    }
 
-            let decoded_byte = self.cipher.decode(byte, self.client_pos);
-            decoded_request.push(decoded_byte as char);
+    pub async fn write(&mut self, response: String) -> Result<(), String> {
+        write_response(self, response).await
+    }
 
-            self.client_pos += 1;
+    /// Splits the layer into a read half and a write half that can be used
+    /// from different tasks. Each half keeps its own stream position and
+    /// both share the cipher.
+    pub fn into_split(self) -> (IslReadHalf<S>, IslWriteHalf<S>) {
+        let InsecureSocketLayer {
+            reader,
+            mut decoder,
+            encoder,
+        } = self;
 
-            if decoded_byte as char == '\n' {
-                break;
-            }
-        }
+        // Bytes already buffered off the wire would be lost with the
+        // BufReader, so decode them now.
+        decoder.push_encoded(reader.buffer());
+
+        let (read, write) = tokio::io::split(reader.into_inner());
+        (
+            IslReadHalf {
+                reader: BufReader::new(read),
+                decoder,
+            },
+            IslWriteHalf {
+                writer: write,
+                encoder,
+            },
+        )
+    }
+}
 
-        Ok(decoded_request)
+impl<S> IslReadHalf<S>
+where
+    S: AsyncRead + Unpin,
+{
+    /// See [`InsecureSocketLayer::read`].
+    pub async fn read(&mut self) -> Result<String, String> {
+        request_string(self.read_line().await?)
     }
 
+    /// See [`InsecureSocketLayer::read_line`].
+    pub async fn read_line(&mut self) -> Result<Vec<u8>, String> {
+        let max_line_length = self.decoder.max_line_length;
+        read_line(self, max_line_length).await
+    }
+
+    /// See [`InsecureSocketLayer::set_max_line_length`].
+    pub fn set_max_line_length(&mut self, max: usize) {
+        self.decoder.max_line_length = max;
+    }
+}
+
+impl<S> IslWriteHalf<S>
+where
+    S: AsyncWrite + Unpin,
+{
     pub async fn write(&mut self, response: String) -> Result<(), String> {
-        let mut writer = BufWriter::new(self.reader.get_mut());
+        write_response(self, response).await
+    }
+}
 
-        for byte in response.as_bytes() {
-            let encoded_byte = self.cipher.encode(*byte, self.server_pos);
+async fn read_line<R: AsyncBufRead + Unpin>(
+    reader: &mut R,
+    max_line_length: usize,
+) -> Result<Vec<u8>, String> {
+    let mut line = Vec::new();
 
-            writer
-                .write_u8(encoded_byte)
-                .await
-                .map_err(|_| "Failed to write encoded byte")?;
+    loop {
+        let available = reader
+            .fill_buf()
+            .await
+            .map_err(|_| "Failed to read request byte")?;
 
-            self.server_pos += 1;
+        // The stream ended before the newline.
+        if available.is_empty() {
+            return Err(String::from("Failed to read request byte"));
         }
 
-        writer.flush().await.map_err(|_| "Failed to flush writer")?;
-        Ok(())
+        let (len, complete) = match available.iter().position(|byte| *byte == b'\n') {
+            Some(newline) => (newline + 1, true),
+            None => (available.len(), false),
+        };
+
+        if line.len() + len > max_line_length {
+            return Err(format!("Request longer than {} bytes", max_line_length));
+        }
+
+        line.extend_from_slice(&available[..len]);
+        reader.consume(len);
+
+        if complete {
+            return Ok(line);
+        }
+    }
+}
+
+fn request_string(line: Vec<u8>) -> Result<String, String> {
+    String::from_utf8(line).map_err(|_| String::from("Request is not valid UTF-8"))
+}
+
+async fn write_response<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    response: String,
+) -> Result<(), String> {
+    writer
+        .write_all(response.as_bytes())
+        .await
+        .map_err(|_| "Failed to write encoded byte")?;
+
+    writer.flush().await.map_err(|_| "Failed to flush writer")?;
+    Ok(())
+}
+
+impl Decoder {
+    fn new(cipher: Arc<Cipher>) -> Decoder {
+        Decoder {
+            cipher,
+            pos: 0,
+            max_line_length: DEFAULT_MAX_LINE_LENGTH,
+            decoded: Vec::new(),
+            consumed: 0,
+        }
+    }
+
+    /// Decodes `encoded` and appends it to the unconsumed bytes.
+    fn push_encoded(&mut self, encoded: &[u8]) {
+        // Bytes are decoded as they come off the wire, so pos
+        // always matches the stream position of the next byte.
+        self.decoded.drain(..self.consumed);
+        self.consumed = 0;
+        for byte in encoded {
+            self.decoded.push(self.cipher.decode(*byte, self.pos));
+            self.pos += 1;
+        }
+    }
+
+    fn poll_fill_buf<R: AsyncBufRead + Unpin>(
+        &mut self,
+        reader: &mut R,
+        cx: &mut Context<'_>,
+    ) -> Poll<io::Result<&[u8]>> {
+        if self.consumed == self.decoded.len() {
+            let encoded = ready!(Pin::new(&mut *reader).poll_fill_buf(cx))?;
+            let len = encoded.len();
+            self.push_encoded(encoded);
+            Pin::new(reader).consume(len);
+        }
+
+        Poll::Ready(Ok(&self.decoded[self.consumed..]))
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.consumed = (self.consumed + amt).min(self.decoded.len());
+    }
+
+    fn poll_read<R: AsyncBufRead + Unpin>(
+        &mut self,
+        reader: &mut R,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let decoded = ready!(self.poll_fill_buf(reader, cx))?;
+        let len = decoded.len().min(buf.remaining());
+        buf.put_slice(&decoded[..len]);
+        self.consume(len);
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Encoder {
+    fn new(cipher: Arc<Cipher>) -> Encoder {
+        Encoder { cipher, pos: 0 }
+    }
+
+    fn poll_write<W: AsyncWrite + Unpin>(
+        &mut self,
+        writer: &mut W,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        // Encoding only depends on the position, so encode everything and
+        // advance pos by what the stream actually took. The caller
+        // hands the rest back on the next write.
+        let encoded: Vec<u8> = buf
+            .iter()
+            .take(MAX_WRITE_CHUNK)
+            .enumerate()
+            .map(|(i, byte)| self.cipher.encode(*byte, self.pos + i))
+            .collect();
+
+        let written = ready!(Pin::new(writer).poll_write(cx, &encoded))?;
+        self.pos += written;
+
+        Poll::Ready(Ok(written))
+    }
+}
+
+impl<S> AsyncBufRead for InsecureSocketLayer<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        let this = self.get_mut();
+        this.decoder.poll_fill_buf(&mut this.reader, cx)
+    }
+
+    fn consume(self: Pin<&mut Self>, amt: usize) {
+        self.get_mut().decoder.consume(amt);
+    }
+}
+
+impl<S> AsyncRead for InsecureSocketLayer<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        this.decoder.poll_read(&mut this.reader, cx, buf)
+    }
+}
+
+impl<S> AsyncWrite for InsecureSocketLayer<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        this.encoder.poll_write(&mut this.reader, cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().reader).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().reader).poll_shutdown(cx)
+    }
+}
+
+impl<S> AsyncBufRead for IslReadHalf<S>
+where
+    S: AsyncRead + Unpin,
+{
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        let this = self.get_mut();
+        this.decoder.poll_fill_buf(&mut this.reader, cx)
+    }
+
+    fn consume(self: Pin<&mut Self>, amt: usize) {
+        self.get_mut().decoder.consume(amt);
+    }
+}
+
+impl<S> AsyncRead for IslReadHalf<S>
+where
+    S: AsyncRead + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        this.decoder.poll_read(&mut this.reader, cx, buf)
+    }
+}
+
+impl<S> AsyncWrite for IslWriteHalf<S>
+where
+    S: AsyncWrite + Unpin,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        this.encoder.poll_write(&mut this.writer, cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().writer).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.get_mut().writer).poll_shutdown(cx)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::get_most_toys;
+    use crate::toys::get_most_toys;
 
     use super::*;
-    use tokio::io::{AsyncWriteExt, DuplexStream};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt, DuplexStream};
     use tokio::runtime::Runtime;
 
     // Helper to write cipher spec and message to a duplex stream
@@ -90,7 +425,7 @@ mod tests {
 
     #[test]
     fn test_xor1_reversebits_hello() {
-        let cipher = [0x02, 0x01, 0x01, 0x00];
+        let cipher = Cipher::builder().xor(1).reverse_bits().build().to_bytes();
         let msg = [0x96, 0x26, 0xb6, 0xb6, 0x76, 0xd0];
         let rt = Runtime::new().unwrap();
         rt.block_on(async {
@@ -104,7 +439,7 @@ mod tests {
 
     #[test]
     fn test_addpos_addpos_hello() {
-        let cipher = [0x05, 0x05, 0x00];
+        let cipher = Cipher::builder().addpos().addpos().build().to_bytes();
         let msg = [0x68, 0x67, 0x70, 0x72, 0x77, 0x14];
         let rt = Runtime::new().unwrap();
         rt.block_on(async {
@@ -118,8 +453,12 @@ mod tests {
 
     #[test]
     fn test_example_session_xor123_addpos_reversebits() {
-        // Cipher: xor(123), addpos, reversebits
-        let cipher = [0x02, 0x7b, 0x05, 0x01, 0x00];
+        let cipher = Cipher::builder()
+            .xor(123)
+            .addpos()
+            .reverse_bits()
+            .build()
+            .to_bytes();
         // First client request (obfuscated): 4x dog,5x car\n
         let req1 = [
             0xf2, 0x20, 0xba, 0x44, 0x18, 0x84, 0xba, 0xaa, 0xd0, 0x26, 0x44, 0xa4, 0xa8, 0x7e,
@@ -176,4 +515,245 @@ mod tests {
             assert_eq!(&buf2, &resp2);
         });
     }
+
+    #[test]
+    fn test_async_read_in_small_pieces() {
+        let cipher = Cipher::builder().xor(1).reverse_bits().build().to_bytes();
+        let msg = [0x96, 0x26, 0xb6, 0xb6, 0x76, 0xd0];
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            // A tiny pipe makes the ISL see the request a couple of bytes at a time.
+            let (mut client, server) = tokio::io::duplex(2);
+            tokio::spawn(async move {
+                write_cipher_and_message(&mut client, &cipher, &msg).await;
+                write_cipher_and_message(&mut client, &[], &msg).await;
+            });
+
+            let mut isl = InsecureSocketLayer::new(server).await.unwrap();
+            let mut decoded = Vec::new();
+            let mut buf = [0u8; 4];
+            while decoded.len() < 6 {
+                let n = AsyncReadExt::read(&mut isl, &mut buf).await.unwrap();
+                decoded.extend_from_slice(&buf[..n]);
+            }
+            assert_eq!(decoded, b"hello\n");
+
+            // Second copy was encoded at positions 6..12, which xor(1) and
+            // reversebits ignore, so it decodes the same.
+            let mut line = String::new();
+            AsyncBufReadExt::read_line(&mut isl, &mut line)
+                .await
+                .unwrap();
+            assert_eq!(line, "hello\n");
+        });
+    }
+
+    #[test]
+    fn test_async_read_line_addpos() {
+        let cipher = Cipher::builder()
+            .xor(123)
+            .addpos()
+            .reverse_bits()
+            .build()
+            .to_bytes();
+        // 4x dog,5x car\n then 3x rat,2x cat\n
+        let requests = [
+            0xf2, 0x20, 0xba, 0x44, 0x18, 0x84, 0xba, 0xaa, 0xd0, 0x26, 0x44, 0xa4, 0xa8, 0x7e,
+            0x6a, 0x48, 0xd6, 0x58, 0x34, 0x44, 0xd6, 0x7a, 0x98, 0x4e, 0x0c, 0xcc, 0x94, 0x31,
+        ];
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (mut client, server) = tokio::io::duplex(5);
+            tokio::spawn(async move {
+                write_cipher_and_message(&mut client, &cipher, &requests).await;
+            });
+
+            let isl = InsecureSocketLayer::new(server).await.unwrap();
+            let mut lines = isl.lines();
+            assert_eq!(lines.next_line().await.unwrap().unwrap(), "4x dog,5x car");
+            assert_eq!(lines.next_line().await.unwrap().unwrap(), "3x rat,2x cat");
+        });
+    }
+
+    #[test]
+    fn test_async_write_partial_writes() {
+        let cipher = Cipher::builder()
+            .xor(123)
+            .addpos()
+            .reverse_bits()
+            .build()
+            .to_bytes();
+        // Both server responses from the example session: 5x car\n3x rat\n
+        let responses = [
+            0x72, 0x20, 0xba, 0xd8, 0x78, 0x70, 0xee, 0xf2, 0xd0, 0x26, 0xc8, 0xa4, 0xd8, 0x7e,
+        ];
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            // The pipe only takes 3 bytes at a time, so writes come back short.
+            let (mut client, server) = tokio::io::duplex(3);
+            let reader = tokio::spawn(async move {
+                client.write_all(&cipher).await.unwrap();
+                let mut buf = [0u8; 14];
+                client.read_exact(&mut buf).await.unwrap();
+                buf
+            });
+
+            let mut isl = InsecureSocketLayer::new(server).await.unwrap();
+
+            isl.write_all(b"5x c").await.unwrap();
+            isl.write_all(b"ar\n3x rat\n").await.unwrap();
+            isl.flush().await.unwrap();
+
+            assert_eq!(reader.await.unwrap(), responses);
+        });
+    }
+
+    #[test]
+    fn test_into_split_keeps_buffered_requests() {
+        let cipher = Cipher::builder()
+            .xor(123)
+            .addpos()
+            .reverse_bits()
+            .build()
+            .to_bytes();
+        // Both client requests from the example session
+        let requests = [
+            0xf2, 0x20, 0xba, 0x44, 0x18, 0x84, 0xba, 0xaa, 0xd0, 0x26, 0x44, 0xa4, 0xa8, 0x7e,
+            0x6a, 0x48, 0xd6, 0x58, 0x34, 0x44, 0xd6, 0x7a, 0x98, 0x4e, 0x0c, 0xcc, 0x94, 0x31,
+        ];
+        // Both server responses from the example session: 5x car\n3x rat\n
+        let responses = [
+            0x72, 0x20, 0xba, 0xd8, 0x78, 0x70, 0xee, 0xf2, 0xd0, 0x26, 0xc8, 0xa4, 0xd8, 0x7e,
+        ];
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (mut client, server) = tokio::io::duplex(64);
+            write_cipher_and_message(&mut client, &cipher, &requests).await;
+
+            // The second request is already buffered when the layer is split.
+            let mut isl = InsecureSocketLayer::new(server).await.unwrap();
+            assert_eq!(isl.read().await.unwrap(), "4x dog,5x car\n");
+            let (mut reader, mut writer) = isl.into_split();
+
+            let writing = tokio::spawn(async move {
+                for response in ["5x car\n", "3x rat\n"] {
+                    writer.write(String::from(response)).await.unwrap();
+                }
+            });
+            assert_eq!(reader.read().await.unwrap(), "3x rat,2x cat\n");
+            writing.await.unwrap();
+
+            let mut buf = [0u8; 14];
+            client.read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf, responses);
+        });
+    }
+
+    #[test]
+    fn test_connect_example_session() {
+        // Requests and responses from the example session, as seen on the wire
+        let requests = [
+            0xf2, 0x20, 0xba, 0x44, 0x18, 0x84, 0xba, 0xaa, 0xd0, 0x26, 0x44, 0xa4, 0xa8, 0x7e,
+            0x6a, 0x48, 0xd6, 0x58, 0x34, 0x44, 0xd6, 0x7a, 0x98, 0x4e, 0x0c, 0xcc, 0x94, 0x31,
+        ];
+        let responses = [
+            0x72, 0x20, 0xba, 0xd8, 0x78, 0x70, 0xee, 0xf2, 0xd0, 0x26, 0xc8, 0xa4, 0xd8, 0x7e,
+        ];
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (mut server, client) = tokio::io::duplex(64);
+            let cipher = Cipher::builder().xor(123).addpos().reverse_bits().build();
+            let mut isl = InsecureSocketLayer::connect(client, cipher).await.unwrap();
+
+            isl.write(String::from("4x dog,5x car\n")).await.unwrap();
+            isl.write(String::from("3x rat,2x cat\n")).await.unwrap();
+
+            let mut buf = [0u8; 33];
+            server.read_exact(&mut buf).await.unwrap();
+            assert_eq!(buf[..5], [0x02, 0x7b, 0x05, 0x01, 0x00]);
+            assert_eq!(buf[5..], requests);
+
+            server.write_all(&responses).await.unwrap();
+            assert_eq!(isl.read().await.unwrap(), "5x car\n");
+            assert_eq!(isl.read().await.unwrap(), "3x rat\n");
+        });
+    }
+
+    #[test]
+    fn test_connect_to_server() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (client, server) = tokio::io::duplex(64);
+            let server = tokio::spawn(async move {
+                let mut isl = InsecureSocketLayer::new(server).await.unwrap();
+                let request = isl.read().await.unwrap();
+                isl.write(get_most_toys(request).unwrap()).await.unwrap();
+            });
+
+            let cipher = "xorpos,add(7),reversebits".parse().unwrap();
+            let mut isl = InsecureSocketLayer::connect(client, cipher).await.unwrap();
+            isl.write(String::from(
+                "10x toy car,15x dog on a string,4x inflatable motorcycle\n",
+            ))
+            .await
+            .unwrap();
+            assert_eq!(isl.read().await.unwrap(), "15x dog on a string\n");
+            server.await.unwrap();
+
+            let (client, _server) = tokio::io::duplex(64);
+            let no_op = Cipher::builder().reverse_bits().reverse_bits().build();
+            assert!(InsecureSocketLayer::connect(client, no_op).await.is_err());
+        });
+    }
+
+    #[test]
+    fn test_max_line_length() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (client, server) = tokio::io::duplex(64);
+            let cipher = Cipher::builder().xorpos().build();
+            let client = tokio::spawn(async move {
+                let mut isl = InsecureSocketLayer::connect(client, cipher).await.unwrap();
+                isl.write(String::from("1x dog\n")).await.unwrap();
+                // Never sends a newline.
+                isl.write("9x cat,".repeat(5)).await.unwrap();
+                isl
+            });
+
+            let mut isl = InsecureSocketLayer::new(server).await.unwrap();
+            isl.set_max_line_length(16);
+            assert_eq!(isl.read().await.unwrap(), "1x dog\n");
+            assert_eq!(
+                isl.read().await,
+                Err(String::from("Request longer than 16 bytes"))
+            );
+            client.await.unwrap();
+        });
+    }
+
+    #[test]
+    fn test_read_line_is_binary_safe() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (client, server) = tokio::io::duplex(64);
+            let cipher = Cipher::builder().xor(1).addpos().build();
+            let client = tokio::spawn(async move {
+                let mut isl = InsecureSocketLayer::connect(client, cipher).await.unwrap();
+                isl.write_all(b"2x caf\xc3\xa9\n").await.unwrap();
+                isl.write_all(b"2x caf\xe9\n").await.unwrap();
+                isl.write_all(b"2x caf\xe9\n").await.unwrap();
+                isl.flush().await.unwrap();
+                isl
+            });
+
+            let mut isl = InsecureSocketLayer::new(server).await.unwrap();
+            assert_eq!(isl.read().await.unwrap(), "2x café\n");
+            assert_eq!(isl.read_line().await.unwrap(), b"2x caf\xe9\n");
+            assert_eq!(
+                isl.read().await,
+                Err(String::from("Request is not valid UTF-8"))
+            );
+            client.await.unwrap();
+        });
+    }
 }
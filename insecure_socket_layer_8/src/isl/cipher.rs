@@ -1,50 +1,376 @@
-use std::ops::BitXor;
+use std::fmt;
+use std::mem;
+use std::str::FromStr;
 use tokio::io::{AsyncRead, AsyncReadExt, BufReader};
 
-enum Operation {
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
     ReverseBits,
     Xor(u8),
     Xorpos,
     Add(u8),
     Addpos,
+    /// Rotates the bits left by n.
+    #[cfg(feature = "extended-ops")]
+    RotateLeft(u8),
+    /// Subtracts the position, undoing `Addpos`.
+    #[cfg(feature = "extended-ops")]
+    Subpos,
+    /// Xors with a keystream derived from the seed. The keystream repeats
+    /// every 256 bytes like the other position-dependent operations.
+    #[cfg(feature = "extended-ops")]
+    Keystream(u8),
+    /// Swaps the high and low nibbles.
+    #[cfg(feature = "extended-ops")]
+    NibbleSwap,
 }
 
-impl Operation {
-    fn encode(&self, byte: u8, pos: usize) -> u8 {
-        match self {
-            Operation::ReverseBits => byte.reverse_bits(),
-            Operation::Xor(n) => byte.bitxor(n),
-            Operation::Xorpos => {
-                let res = (byte as usize ^ pos) % 256;
-                res as u8
-            }
-            Operation::Add(n) => byte.wrapping_add(*n),
-            Operation::Addpos => {
-                let res = ((byte as usize).wrapping_add(pos)) % 256;
-                res as u8
+/// How an operation appears in a cipher spec, on the wire and as text, and
+/// what it does to the bytes it is applied to.
+pub struct Registration {
+    pub opcode: u8,
+    pub name: &'static str,
+    /// Argument bytes following the opcode, either 0 or 1.
+    pub arg_len: usize,
+    /// Builds the operation from its argument, 0 if it takes none.
+    pub make: fn(u8) -> Operation,
+    /// Encodes a byte at a stream position, called as `(arg, byte, pos)`.
+    pub encode: fn(u8, u8, usize) -> u8,
+    /// Undoes `encode`, called the same way.
+    pub decode: fn(u8, u8, usize) -> u8,
+    /// The operation whose encoding undoes this one for an argument, if any.
+    pub inverse: fn(u8) -> Option<Operation>,
+    /// Folds the arguments of two adjacent operations of this kind into the
+    /// argument of one, for the operations where that works.
+    pub merge: Option<fn(u8, u8) -> u8>,
+    pub depends_on_position: bool,
+}
+
+/// Every operation a cipher spec may contain. The extended operations are
+/// not part of the protocol and need the `extended-ops` feature.
+pub const REGISTRY: &[Registration] = &[
+    Registration {
+        opcode: 0x01,
+        name: "reversebits",
+        arg_len: 0,
+        make: |_| Operation::ReverseBits,
+        encode: |_, byte, _| byte.reverse_bits(),
+        decode: |_, byte, _| byte.reverse_bits(),
+        inverse: |_| Some(Operation::ReverseBits),
+        merge: None,
+        depends_on_position: false,
+    },
+    Registration {
+        opcode: 0x02,
+        name: "xor",
+        arg_len: 1,
+        make: Operation::Xor,
+        encode: |n, byte, _| byte ^ n,
+        decode: |n, byte, _| byte ^ n,
+        inverse: |n| Some(Operation::Xor(n)),
+        merge: Some(|a, b| a ^ b),
+        depends_on_position: false,
+    },
+    Registration {
+        opcode: 0x03,
+        name: "xorpos",
+        arg_len: 0,
+        make: |_| Operation::Xorpos,
+        encode: |_, byte, pos| byte ^ pos as u8,
+        decode: |_, byte, pos| byte ^ pos as u8,
+        inverse: |_| Some(Operation::Xorpos),
+        merge: None,
+        depends_on_position: true,
+    },
+    Registration {
+        opcode: 0x04,
+        name: "add",
+        arg_len: 1,
+        make: Operation::Add,
+        encode: |n, byte, _| byte.wrapping_add(n),
+        decode: |n, byte, _| byte.wrapping_sub(n),
+        inverse: |n| Some(Operation::Add(n.wrapping_neg())),
+        merge: Some(u8::wrapping_add),
+        depends_on_position: false,
+    },
+    Registration {
+        opcode: 0x05,
+        name: "addpos",
+        arg_len: 0,
+        make: |_| Operation::Addpos,
+        encode: |_, byte, pos| byte.wrapping_add(pos as u8),
+        decode: |_, byte, pos| byte.wrapping_sub(pos as u8),
+        // Only registered with the extended operations.
+        inverse: |_| "subpos".parse().ok(),
+        merge: None,
+        depends_on_position: true,
+    },
+    #[cfg(feature = "extended-ops")]
+    Registration {
+        opcode: 0x06,
+        name: "rotl",
+        arg_len: 1,
+        make: Operation::RotateLeft,
+        encode: |n, byte, _| byte.rotate_left(n as u32 % 8),
+        decode: |n, byte, _| byte.rotate_right(n as u32 % 8),
+        inverse: |n| Some(Operation::RotateLeft((8 - n % 8) % 8)),
+        merge: Some(|a, b| (a % 8 + b % 8) % 8),
+        depends_on_position: false,
+    },
+    #[cfg(feature = "extended-ops")]
+    Registration {
+        opcode: 0x07,
+        name: "subpos",
+        arg_len: 0,
+        make: |_| Operation::Subpos,
+        encode: |_, byte, pos| byte.wrapping_sub(pos as u8),
+        decode: |_, byte, pos| byte.wrapping_add(pos as u8),
+        inverse: |_| Some(Operation::Addpos),
+        merge: None,
+        depends_on_position: true,
+    },
+    #[cfg(feature = "extended-ops")]
+    Registration {
+        opcode: 0x08,
+        name: "keystream",
+        arg_len: 1,
+        make: Operation::Keystream,
+        encode: |seed, byte, pos| byte ^ keystream(seed, pos),
+        decode: |seed, byte, pos| byte ^ keystream(seed, pos),
+        inverse: |seed| Some(Operation::Keystream(seed)),
+        merge: None,
+        depends_on_position: true,
+    },
+    #[cfg(feature = "extended-ops")]
+    Registration {
+        opcode: 0x09,
+        name: "nibbleswap",
+        arg_len: 0,
+        make: |_| Operation::NibbleSwap,
+        encode: |_, byte, _| byte.rotate_left(4),
+        decode: |_, byte, _| byte.rotate_right(4),
+        inverse: |_| Some(Operation::NibbleSwap),
+        merge: None,
+        depends_on_position: false,
+    },
+];
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = self.registration().name;
+        match self.arg() {
+            Some(n) => write!(f, "{}({})", name, n),
+            None => write!(f, "{}", name),
+        }
+    }
+}
+
+/// Parses a single operation as written by `Display`, e.g. `xor(123)`.
+/// Arguments may also be given in hex, e.g. `add(0x7b)`.
+impl FromStr for Operation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Operation, String> {
+        let s = s.trim();
+        let invalid = || format!("Invalid cipher operation: {}", s);
+
+        let (name, arg) = match s.split_once('(') {
+            Some((name, rest)) => {
+                let arg = rest.strip_suffix(')').ok_or_else(invalid)?.trim();
+                let arg = match arg.strip_prefix("0x") {
+                    Some(hex) => u8::from_str_radix(hex, 16),
+                    None => arg.parse::<u8>(),
+                };
+                (name.trim(), Some(arg.map_err(|_| invalid())?))
             }
+            None => (s, None),
+        };
+
+        let registration = REGISTRY
+            .iter()
+            .find(|r| r.name == name)
+            .ok_or_else(invalid)?;
+
+        match (registration.arg_len, arg) {
+            (0, None) => Ok((registration.make)(0)),
+            (1, Some(n)) => Ok((registration.make)(n)),
+            _ => Err(invalid()),
         }
     }
+}
+
+/// Keystream byte for `seed` at `pos`, repeating every 256 positions.
+#[cfg(feature = "extended-ops")]
+fn keystream(seed: u8, pos: usize) -> u8 {
+    let mut x = ((seed as u32) << 8) | (pos % 256) as u32;
+    x = x.wrapping_mul(0x9e3779b1);
+    x ^= x >> 15;
+    x = x.wrapping_mul(0x85ebca77);
+    x ^= x >> 13;
+    (x >> 24) as u8
+}
+
+impl Operation {
+    pub fn opcode(&self) -> u8 {
+        self.registration().opcode
+    }
 
-    fn decode(&self, byte: u8, pos: usize) -> u8 {
+    pub fn arg(&self) -> Option<u8> {
         match self {
-            Operation::ReverseBits => byte.reverse_bits(),
-            Operation::Xor(n) => byte.bitxor(n),
-            Operation::Xorpos => {
-                let res = (byte as usize ^ pos) % 256;
-                res as u8
-            }
-            Operation::Add(n) => byte.wrapping_sub(*n),
-            Operation::Addpos => {
-                let res = ((byte as usize).wrapping_sub(pos)) % 256;
-                res as u8
+            Operation::Xor(n) | Operation::Add(n) => Some(*n),
+            #[cfg(feature = "extended-ops")]
+            Operation::RotateLeft(n) | Operation::Keystream(n) => Some(*n),
+            _ => None,
+        }
+    }
+
+    pub fn registration(&self) -> &'static Registration {
+        let kind = mem::discriminant(self);
+        REGISTRY
+            .iter()
+            .find(|r| mem::discriminant(&(r.make)(0)) == kind)
+            .expect("every operation is registered")
+    }
+
+    /// The operation whose encoding undoes this one, if there is one.
+    pub fn inverse(&self) -> Option<Operation> {
+        (self.registration().inverse)(self.arg().unwrap_or(0))
+    }
+
+    /// Appends the wire encoding of the operation.
+    fn write_bytes(&self, bytes: &mut Vec<u8>) {
+        bytes.push(self.opcode());
+        bytes.extend(self.arg());
+    }
+
+    /// Merges two adjacent operations, `self` applied first.
+    ///
+    /// Returns `Some(None)` if the pair cancels out, `Some(Some(op))` if it
+    /// collapses into a single operation and `None` if it doesn't simplify.
+    fn combine(&self, next: &Operation) -> Option<Option<Operation>> {
+        let registration = self.registration();
+        let merge = registration
+            .merge
+            .filter(|_| next.opcode() == registration.opcode);
+        if let Some(merge) = merge {
+            let arg = merge(self.arg().unwrap_or(0), next.arg().unwrap_or(0));
+            return Some((registration.make)(arg).non_trivial());
+        }
+
+        if self.inverse() == Some(*next) {
+            Some(None)
+        } else {
+            None
+        }
+    }
+
+    /// `None` for operations that leave every byte unchanged.
+    fn non_trivial(self) -> Option<Operation> {
+        let registration = self.registration();
+        let arg = self.arg().unwrap_or(0);
+        let unchanged = !registration.depends_on_position
+            && (0..=255u8).all(|byte| (registration.encode)(arg, byte, 0) == byte);
+        (!unchanged).then_some(self)
+    }
+
+    pub fn encode(&self, byte: u8, pos: usize) -> u8 {
+        (self.registration().encode)(self.arg().unwrap_or(0), byte, pos)
+    }
+
+    pub fn decode(&self, byte: u8, pos: usize) -> u8 {
+        (self.registration().decode)(self.arg().unwrap_or(0), byte, pos)
+    }
+}
+
+/// Reduces an operation list to its canonical form by folding adjacent
+/// operations that combine or cancel out, e.g. `xor(1),xor(2)` into `xor(3)`
+/// and `reversebits,reversebits` into nothing.
+pub(crate) fn simplify(operations: &[Operation]) -> Vec<Operation> {
+    let mut simplified: Vec<Operation> = Vec::new();
+
+    for op in operations {
+        let Some(mut op) = op.non_trivial() else {
+            continue;
+        };
+
+        // Folding can expose a new pair further down, e.g. the outer xors in
+        // xor(1),reversebits,reversebits,xor(1).
+        loop {
+            match simplified.last().and_then(|last| last.combine(&op)) {
+                Some(combined) => {
+                    simplified.pop();
+                    match combined {
+                        Some(combined) => op = combined,
+                        None => break,
+                    }
+                }
+                None => {
+                    simplified.push(op);
+                    break;
+                }
             }
         }
     }
+
+    simplified
+}
+
+/// Positions only matter modulo this, every position-dependent operation
+/// truncates the position to a byte.
+const POSITION_CYCLE: usize = 256;
+
+/// Builds a cipher one operation at a time, e.g.
+/// `Cipher::builder().xor(1).reverse_bits().build()`.
+#[derive(Debug, Default, Clone)]
+pub struct CipherBuilder {
+    operations: Vec<Operation>,
+}
+
+impl CipherBuilder {
+    pub fn reverse_bits(self) -> CipherBuilder {
+        self.operation(Operation::ReverseBits)
+    }
+
+    pub fn xor(self, n: u8) -> CipherBuilder {
+        self.operation(Operation::Xor(n))
+    }
+
+    pub fn xorpos(self) -> CipherBuilder {
+        self.operation(Operation::Xorpos)
+    }
+
+    // Named after the spec's add(N), not std::ops::Add.
+    #[allow(clippy::should_implement_trait)]
+    pub fn add(self, n: u8) -> CipherBuilder {
+        self.operation(Operation::Add(n))
+    }
+
+    pub fn addpos(self) -> CipherBuilder {
+        self.operation(Operation::Addpos)
+    }
+
+    pub fn operation(mut self, op: Operation) -> CipherBuilder {
+        self.operations.push(op);
+        self
+    }
+
+    pub fn operations(mut self, ops: impl IntoIterator<Item = Operation>) -> CipherBuilder {
+        self.operations.extend(ops);
+        self
+    }
+
+    pub fn build(self) -> Cipher {
+        Cipher::from_operations(self.operations)
+    }
 }
 
 pub struct Cipher {
     operations: Vec<Operation>,
+    /// Encoded byte for every input byte, one table per position residue.
+    /// Holds a single table when no operation depends on the position.
+    encode_table: Vec<[u8; 256]>,
+    /// Inverse of `encode_table`.
+    decode_table: Vec<[u8; 256]>,
 }
 
 async fn read_cipher_byte<R: AsyncRead + Unpin>(reader: &mut BufReader<R>) -> Result<u8, String> {
@@ -55,6 +381,10 @@ async fn read_cipher_byte<R: AsyncRead + Unpin>(reader: &mut BufReader<R>) -> Re
 }
 
 impl Cipher {
+    pub fn builder() -> CipherBuilder {
+        CipherBuilder::default()
+    }
+
     pub async fn new<R: AsyncRead + Unpin>(reader: &mut BufReader<R>) -> Result<Cipher, String> {
         let mut operations = Vec::new();
 
@@ -65,20 +395,16 @@ impl Cipher {
                 break;
             }
 
-            let op = match op {
-                0x01 => Operation::ReverseBits,
-                0x02 => {
-                    let n = read_cipher_byte(reader).await?;
-                    Operation::Xor(n)
-                }
-                0x03 => Operation::Xorpos,
-                0x04 => {
-                    let n = read_cipher_byte(reader).await?;
-                    Operation::Add(n)
-                }
-                0x05 => Operation::Addpos,
-                _ => return Err(String::from("Invalid cipher operation")),
+            let registration = REGISTRY
+                .iter()
+                .find(|r| r.opcode == op)
+                .ok_or("Invalid cipher operation")?;
+
+            let arg = match registration.arg_len {
+                0 => 0,
+                _ => read_cipher_byte(reader).await?,
             };
+            let op = (registration.make)(arg);
 
             operations.push(op);
         }
@@ -86,29 +412,122 @@ impl Cipher {
         if operations.is_empty() {
             Err(String::from("Empty cipher"))
         } else {
-            Ok(Cipher { operations })
+            Ok(Cipher::from_operations(operations))
+        }
+    }
+
+    fn from_operations(operations: Vec<Operation>) -> Cipher {
+        let steps: Vec<(&Registration, u8)> = operations
+            .iter()
+            .map(|op| (op.registration(), op.arg().unwrap_or(0)))
+            .collect();
+
+        let positions = if steps.iter().any(|(r, _)| r.depends_on_position) {
+            POSITION_CYCLE
+        } else {
+            1
+        };
+
+        let mut encode_table = vec![[0u8; 256]; positions];
+        let mut decode_table = vec![[0u8; 256]; positions];
+        for pos in 0..positions {
+            for byte in 0..=255u8 {
+                encode_table[pos][byte as usize] = steps
+                    .iter()
+                    .fold(byte, |byte, (r, arg)| (r.encode)(*arg, byte, pos));
+                decode_table[pos][byte as usize] = steps
+                    .iter()
+                    .rev()
+                    .fold(byte, |byte, (r, arg)| (r.decode)(*arg, byte, pos));
+            }
+        }
+
+        Cipher {
+            operations,
+            encode_table,
+            decode_table,
         }
     }
 
+    /// Whether the cipher leaves every byte unchanged at every position.
     pub fn is_no_op(&self) -> bool {
-        let h = 'h';
-        self.encode(h as u8, 0) == h as u8
+        if simplify(&self.operations).is_empty() {
+            return true;
+        }
+
+        self.encode_table.iter().all(|table| {
+            table
+                .iter()
+                .enumerate()
+                .all(|(byte, encoded)| byte == *encoded as usize)
+        })
     }
 
-    pub fn decode(&self, mut byte: u8, pos: usize) -> u8 {
-        for op in self.operations.iter().rev() {
-            byte = op.decode(byte, pos);
+    /// The operations as given, without simplification.
+    pub fn operations(&self) -> &[Operation] {
+        &self.operations
+    }
+
+    /// The cipher spec as sent on the wire, terminated by 0x00.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for op in &self.operations {
+            op.write_bytes(&mut bytes);
         }
+        bytes.push(0x00);
+        bytes
+    }
 
-        byte
+    /// The simplified cipher spec, e.g. `xor(3),addpos` for
+    /// `xor(1),xor(2),reversebits,reversebits,addpos`. Empty if the
+    /// operations cancel out entirely.
+    pub fn canonical_form(&self) -> String {
+        simplify(&self.operations)
+            .iter()
+            .map(|op| op.to_string())
+            .collect::<Vec<String>>()
+            .join(",")
     }
 
-    pub fn encode(&self, mut byte: u8, pos: usize) -> u8 {
-        for op in self.operations.iter() {
-            byte = op.encode(byte, pos);
+    pub fn decode(&self, byte: u8, pos: usize) -> u8 {
+        let table = &self.decode_table[pos % self.decode_table.len()];
+        table[byte as usize]
+    }
+
+    pub fn encode(&self, byte: u8, pos: usize) -> u8 {
+        let table = &self.encode_table[pos % self.encode_table.len()];
+        table[byte as usize]
+    }
+}
+
+/// Formats the cipher spec as written, e.g. `xor(123),addpos,reversebits`.
+impl fmt::Display for Cipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, op) in self.operations.iter().enumerate() {
+            if i > 0 {
+                write!(f, ",")?;
+            }
+            write!(f, "{}", op)?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses a comma-separated cipher spec, e.g. `xor(123),addpos,reversebits`.
+impl FromStr for Cipher {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Cipher, String> {
+        if s.trim().is_empty() {
+            return Err(String::from("Empty cipher"));
         }
 
-        byte
+        let operations = s
+            .split(',')
+            .map(str::parse)
+            .collect::<Result<Vec<Operation>, String>>()?;
+
+        Ok(Cipher::from_operations(operations))
     }
 }
 
@@ -119,7 +538,7 @@ mod tests {
     #[test]
     fn basic_cipher() {
         let ops = vec![Operation::Xor(1), Operation::ReverseBits];
-        let cipher = Cipher { operations: ops };
+        let cipher = Cipher::from_operations(ops);
 
         let input: Vec<u8> = vec![0x68, 0x65, 0x6c, 0x6c, 0x6f]; // hello
         let expected_output: Vec<u8> = vec![0x96, 0x26, 0xb6, 0xb6, 0x76];
@@ -144,7 +563,7 @@ mod tests {
     #[test]
     fn basic_cipher2() {
         let ops = vec![Operation::Addpos, Operation::Addpos];
-        let cipher = Cipher { operations: ops };
+        let cipher = Cipher::from_operations(ops);
 
         let input: Vec<u8> = vec![0x68, 0x65, 0x6c, 0x6c, 0x6f]; // hello
         let expected_output: Vec<u8> = vec![0x68, 0x67, 0x70, 0x72, 0x77];
@@ -165,4 +584,268 @@ mod tests {
 
         assert_eq!(output, input);
     }
+
+    #[test]
+    fn tables_match_operations() {
+        let ops = [
+            Operation::Xor(123),
+            Operation::Addpos,
+            Operation::ReverseBits,
+            Operation::Xorpos,
+            Operation::Add(7),
+        ];
+        let cipher = Cipher::from_operations(ops.to_vec());
+        assert_eq!(cipher.encode_table.len(), 256);
+
+        for pos in [0, 1, 255, 256, 257, 1000, 65537] {
+            for byte in 0..=255u8 {
+                let encoded = ops.iter().fold(byte, |byte, op| op.encode(byte, pos));
+                assert_eq!(cipher.encode(byte, pos), encoded);
+                assert_eq!(cipher.decode(encoded, pos), byte);
+            }
+        }
+
+        let cipher = Cipher::from_operations(vec![Operation::Xor(1), Operation::ReverseBits]);
+        assert_eq!(cipher.encode_table.len(), 1);
+        assert_eq!(cipher.encode(0x68, 1000), 0x96);
+        assert_eq!(cipher.decode(0x96, 1000), 0x68);
+    }
+
+    #[test]
+    fn no_op_ciphers() {
+        let no_ops = [
+            vec![Operation::Xor(0)],
+            vec![Operation::Add(0)],
+            vec![Operation::ReverseBits, Operation::ReverseBits],
+            vec![
+                Operation::Xor(0xa0),
+                Operation::Xor(0x0b),
+                Operation::Xor(0xab),
+            ],
+            vec![Operation::Xorpos, Operation::Xorpos],
+            vec![Operation::Add(200), Operation::Add(56)],
+            // Only caught by the exhaustive check.
+            vec![
+                Operation::Xor(0xff),
+                Operation::ReverseBits,
+                Operation::Xor(0xff),
+                Operation::ReverseBits,
+            ],
+        ];
+        for ops in no_ops {
+            assert!(Cipher::from_operations(ops.clone()).is_no_op(), "{:?}", ops);
+        }
+
+        let ciphers = [
+            vec![Operation::Addpos, Operation::Addpos],
+            vec![Operation::Xor(1), Operation::ReverseBits],
+            vec![Operation::Xorpos],
+            // Leaves every byte at position 0 alone.
+            vec![Operation::Addpos],
+            vec![Operation::Xor(0x01), Operation::Add(0x01)],
+        ];
+        for ops in ciphers {
+            assert!(
+                !Cipher::from_operations(ops.clone()).is_no_op(),
+                "{:?}",
+                ops
+            );
+        }
+    }
+
+    #[test]
+    fn canonical_form() {
+        let cases = [
+            (
+                vec![
+                    Operation::Xor(1),
+                    Operation::Xor(2),
+                    Operation::ReverseBits,
+                    Operation::ReverseBits,
+                    Operation::Addpos,
+                ],
+                "xor(3),addpos",
+            ),
+            (
+                vec![
+                    Operation::Xor(1),
+                    Operation::ReverseBits,
+                    Operation::ReverseBits,
+                    Operation::Xor(1),
+                ],
+                "",
+            ),
+            (
+                vec![Operation::Add(250), Operation::Add(10), Operation::Xor(0)],
+                "add(4)",
+            ),
+            (
+                vec![
+                    Operation::Xor(123),
+                    Operation::Addpos,
+                    Operation::ReverseBits,
+                ],
+                "xor(123),addpos,reversebits",
+            ),
+            (vec![Operation::Addpos, Operation::Addpos], "addpos,addpos"),
+        ];
+        for (ops, canonical) in cases {
+            assert_eq!(Cipher::from_operations(ops).canonical_form(), canonical);
+        }
+    }
+
+    #[test]
+    fn builder_and_spec_strings() {
+        let cipher = Cipher::builder().xor(1).reverse_bits().build();
+        assert_eq!(cipher.to_bytes(), [0x02, 0x01, 0x01, 0x00]);
+        assert_eq!(cipher.to_string(), "xor(1),reversebits");
+
+        let cipher = Cipher::builder().xor(123).addpos().reverse_bits().build();
+        assert_eq!(cipher.to_bytes(), [0x02, 0x7b, 0x05, 0x01, 0x00]);
+
+        let cipher = Cipher::builder().add(4).xorpos().addpos().build();
+        assert_eq!(cipher.to_bytes(), [0x04, 0x04, 0x03, 0x05, 0x00]);
+
+        let parsed: Cipher = "xor(123), addpos,reversebits".parse().unwrap();
+        assert_eq!(parsed.to_bytes(), [0x02, 0x7b, 0x05, 0x01, 0x00]);
+        assert_eq!(parsed.to_string(), "xor(123),addpos,reversebits");
+
+        let parsed: Cipher = "add(0x7b),xorpos".parse().unwrap();
+        assert_eq!(
+            parsed.operations(),
+            [Operation::Add(123), Operation::Xorpos]
+        );
+
+        for spec in [
+            "",
+            "xor",
+            "xor(256)",
+            "add(1",
+            "addpos(1)",
+            "rot13",
+            "xor(1),",
+        ] {
+            assert!(spec.parse::<Cipher>().is_err(), "{:?}", spec);
+        }
+    }
+
+    #[test]
+    fn spec_round_trip() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let cipher = Cipher::builder().xor(0).addpos().add(255).xorpos().build();
+        let bytes = cipher.to_bytes();
+
+        let parsed = rt
+            .block_on(Cipher::new(&mut BufReader::new(&bytes[..])))
+            .unwrap();
+        assert_eq!(parsed.operations(), cipher.operations());
+    }
+
+    #[test]
+    fn registry() {
+        for (i, registration) in REGISTRY.iter().enumerate() {
+            assert!(registration.arg_len <= 1);
+            assert!(
+                REGISTRY[i + 1..]
+                    .iter()
+                    .all(|r| r.opcode != registration.opcode && r.name != registration.name),
+                "{} registered twice",
+                registration.name
+            );
+
+            let op = (registration.make)(3);
+            assert_eq!(op.opcode(), registration.opcode);
+            assert_eq!(op.to_string().parse::<Operation>(), Ok(op));
+
+            // Decoding and the inverse undo the encoding, which only varies
+            // with the position if the registration says so.
+            for pos in 0..300 {
+                for byte in 0..=255u8 {
+                    let encoded = op.encode(byte, pos);
+                    assert_eq!(op.decode(encoded, pos), byte, "{}", op);
+                    if let Some(inverse) = op.inverse() {
+                        assert_eq!(inverse.encode(encoded, pos), byte, "{}", op);
+                    }
+                    if !registration.depends_on_position {
+                        assert_eq!(op.encode(byte, 0), encoded, "{}", op);
+                    }
+                }
+            }
+        }
+    }
+
+    #[cfg(not(feature = "extended-ops"))]
+    #[test]
+    fn strict_mode_rejects_extended_ops() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        for spec in [&[0x06, 0x03, 0x00][..], &[0x07, 0x00], &[0x09, 0x00]] {
+            assert!(rt.block_on(Cipher::new(&mut BufReader::new(spec))).is_err());
+        }
+        assert!("nibbleswap".parse::<Cipher>().is_err());
+    }
+
+    #[cfg(feature = "extended-ops")]
+    #[test]
+    fn extended_ops() {
+        let ops = [
+            Operation::RotateLeft(3),
+            Operation::RotateLeft(11),
+            Operation::Subpos,
+            Operation::Keystream(0),
+            Operation::Keystream(42),
+            Operation::NibbleSwap,
+        ];
+
+        for op in ops {
+            let inverse = op.inverse().unwrap();
+            for pos in 0..600 {
+                for byte in 0..=255u8 {
+                    let encoded = op.encode(byte, pos);
+                    assert_eq!(op.decode(encoded, pos), byte, "{}", op);
+                    assert_eq!(inverse.encode(encoded, pos), byte, "{}", op);
+                    assert_eq!(op.encode(byte, pos % 256), encoded, "{}", op);
+                }
+            }
+        }
+
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        let cipher = Cipher::builder().operations(ops).xor(9).build();
+        let parsed = rt
+            .block_on(Cipher::new(&mut BufReader::new(&cipher.to_bytes()[..])))
+            .unwrap();
+        assert_eq!(parsed.operations(), cipher.operations());
+        assert_eq!(
+            cipher.to_string(),
+            "rotl(3),rotl(11),subpos,keystream(0),keystream(42),nibbleswap,xor(9)"
+        );
+    }
+
+    #[cfg(feature = "extended-ops")]
+    #[test]
+    fn extended_no_op_ciphers() {
+        let no_ops = [
+            "rotl(3),rotl(5)",
+            "rotl(8)",
+            "nibbleswap,nibbleswap",
+            "addpos,subpos",
+            "subpos,xor(1),xor(1),addpos",
+            "keystream(7),keystream(7)",
+            // Only caught by the exhaustive check.
+            "rotl(4),nibbleswap",
+        ];
+        for spec in no_ops {
+            assert!(spec.parse::<Cipher>().unwrap().is_no_op(), "{}", spec);
+        }
+
+        for spec in [
+            "rotl(1)",
+            "keystream(7),keystream(8)",
+            "addpos,addpos,subpos",
+        ] {
+            assert!(!spec.parse::<Cipher>().unwrap().is_no_op(), "{}", spec);
+        }
+
+        let cipher: Cipher = "rotl(3),rotl(6),subpos,addpos,nibbleswap".parse().unwrap();
+        assert_eq!(cipher.canonical_form(), "rotl(1),nibbleswap");
+    }
 }
@@ -0,0 +1,4 @@
+pub mod handler;
+pub mod isl;
+pub mod recover;
+pub mod toys;
@@ -1,68 +1,75 @@
-mod isl;
-
-use isl::InsecureSocketLayer;
+use insecure_socket_layer_8::handler::{BudgetChat, Echo, LineHandler, Outbox, PrimeTime, Toys};
+use insecure_socket_layer_8::isl::InsecureSocketLayer;
 use std::io;
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::net::TcpListener;
 
-fn get_most_toys(toys: String) -> Result<String, String> {
-    let parsed: Result<Vec<(u32, String)>, String> = toys
-        .split(',')
-        .map(|x| {
-            let mut parts = x.splitn(2, 'x');
-            let count = parts
-                .next()
-                .ok_or("Invalid input string")?
-                .trim()
-                .parse::<u32>()
-                .map_err(|_| "Invalid input string")?;
-            let name = parts
-                .next()
-                .ok_or("Invalid input string")?
-                .trim()
-                .to_string();
-            Ok((count, name))
-        })
-        .collect();
-
-    let parsed = parsed?;
-    let most_toys = parsed
-        .into_iter()
-        .max_by_key(|(count, _)| *count)
-        .ok_or("Invalid input string")?;
-
-    Ok(format!("{}x {}\n", most_toys.0, most_toys.1))
-}
+const USAGE: &str = "Usage: insecure_socket_layer_8 [toys|echo|prime|chat]";
 
-async fn handle_client<S>(socket: S) -> Result<(), String>
+async fn handle_client<S, H>(socket: S, mut handler: H) -> Result<(), String>
 where
     S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    H: LineHandler,
 {
     //
     // Create the socket layer.
     //
-    let mut isl = InsecureSocketLayer::new(socket).await?;
+    let isl = InsecureSocketLayer::new(socket).await?;
+    let (mut reader, mut writer) = isl.into_split();
 
     //
-    // Process requests.
+    // Write responses from their own task so a slow client does not hold up
+    // reading pipelined requests. Once the outbox is full, answers wait for
+    // the client to catch up, and a client that can't take a pushed line is
+    // disconnected.
     //
-    loop {
-        let request = isl.read().await?;
+    let (responses, mut pending) = Outbox::new();
+    let writing = tokio::spawn(async move {
+        while let Some(response) = pending.recv().await {
+            writer.write(response).await?;
+        }
+        Ok::<(), String>(())
+    });
 
-        let response = get_most_toys(request)?;
+    //
+    // Process requests.
+    //
+    let result = async {
+        handler.connected(&responses).await?;
+        loop {
+            let request = tokio::select! {
+                request = reader.read() => request?,
+                () = responses.failed() => return Err(String::from("Client fell too far behind")),
+            };
+            handler.line(request, &responses).await?;
+        }
+    }
+    .await;
 
-        isl.write(response).await?;
+    //
+    // Let the writer finish the responses already queued, unless the client
+    // was dropped for not reading them.
+    //
+    handler.disconnected();
+    if responses.has_failed() {
+        writing.abort();
+        return result;
     }
+    drop(responses);
+    writing.await.map_err(|_| "Writer task failed")??;
+    result
 }
 
-#[tokio::main]
-async fn main() -> io::Result<()> {
-    let listener = TcpListener::bind("0.0.0.0:10000").await?;
-
+async fn serve<H, F>(listener: TcpListener, new_handler: F) -> io::Result<()>
+where
+    H: LineHandler,
+    F: Fn() -> H,
+{
     loop {
         let (socket, _) = listener.accept().await?;
+        let handler = new_handler();
         tokio::spawn(async move {
-            match handle_client(socket).await {
+            match handle_client(socket, handler).await {
                 Ok(_) => (),
                 Err(str) => println!("{}", str),
             }
@@ -70,37 +77,41 @@ async fn main() -> io::Result<()> {
     }
 }
 
+#[tokio::main]
+async fn main() -> io::Result<()> {
+    let app = std::env::args().nth(1).unwrap_or(String::from("toys"));
+    let listener = TcpListener::bind("0.0.0.0:10000").await?;
+
+    match app.as_str() {
+        "toys" => serve(listener, || Toys).await,
+        "echo" => serve(listener, || Echo).await,
+        "prime" => serve(listener, || PrimeTime).await,
+        "chat" => {
+            let chat = BudgetChat::new();
+            serve(listener, move || chat.client()).await
+        }
+        _ => {
+            println!("{USAGE}");
+            Ok(())
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
-    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use insecure_socket_layer_8::isl::Cipher;
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt};
     use tokio::runtime::Runtime;
 
-    #[test]
-    fn basic_toys() {
-        let s = "1x dog,3x car,2x rat\n";
-        assert_eq!(get_most_toys(String::from(s)).unwrap(), "3x car\n");
-    }
-
-    #[test]
-    fn invalid_toys() {
-        let s = "1x dog,3 car,2x rat\n";
-        assert_eq!(
-            get_most_toys(String::from(s)),
-            Err(String::from("Invalid input string"))
-        );
-
-        let s = "\n";
-        assert_eq!(
-            get_most_toys(String::from(s)),
-            Err(String::from("Invalid input string"))
-        );
-    }
-
     #[test]
     fn test_handle_client_xor123_addpos_reversebits() {
-        // Cipher: xor(123), addpos, reversebits
-        let cipher = [0x02, 0x7b, 0x05, 0x01, 0x00];
+        let cipher = Cipher::builder()
+            .xor(123)
+            .addpos()
+            .reverse_bits()
+            .build()
+            .to_bytes();
         // First client request (obfuscated): 4x dog,5x car\n
         let req1 = [
             0xf2, 0x20, 0xba, 0x44, 0x18, 0x84, 0xba, 0xaa, 0xd0, 0x26, 0x44, 0xa4, 0xa8, 0x7e,
@@ -119,7 +130,7 @@ mod tests {
             let (mut client, server) = tokio::io::duplex(128);
 
             tokio::spawn(async move {
-                match handle_client(server).await {
+                match handle_client(server, Toys).await {
                     Ok(_) => (),
                     Err(str) => println!("{}", str),
                 }
@@ -145,4 +156,92 @@ mod tests {
             assert_eq!(&buf2, &resp2);
         });
     }
+
+    /// Applies xor(1), which is its own inverse.
+    fn xor1(bytes: &[u8]) -> Vec<u8> {
+        bytes.iter().map(|b| b ^ 1).collect()
+    }
+
+    #[test]
+    fn test_handle_client_pipelined_requests() {
+        let cipher = Cipher::builder().xor(1).build();
+        let requests = 20_000;
+
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (client, server) = tokio::io::duplex(1024);
+            let serving = tokio::spawn(handle_client(server, Toys));
+            let (mut client_reader, mut client_writer) = tokio::io::split(client);
+
+            // Send every request before reading, while responses are read
+            // concurrently.
+            let sending = tokio::spawn(async move {
+                client_writer.write_all(&cipher.to_bytes()).await.unwrap();
+                for _ in 0..requests {
+                    client_writer
+                        .write_all(&xor1(b"1x a,2x b\n"))
+                        .await
+                        .unwrap();
+                }
+                client_writer
+            });
+
+            let mut responses = vec![0; requests * 5];
+            client_reader.read_exact(&mut responses).await.unwrap();
+            assert_eq!(xor1(&responses), b"2x b\n".repeat(requests));
+
+            // Hanging up ends the connection.
+            drop(client_reader.unsplit(sending.await.unwrap()));
+            assert!(serving.await.unwrap().is_err());
+        });
+    }
+
+    #[test]
+    fn test_handle_client_disconnects_chat_member_that_never_reads() {
+        let chat = BudgetChat::new();
+        let cipher = Cipher::builder().xor(1).build().to_bytes();
+
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (mut sloth, server) = tokio::io::duplex(1024);
+            let sloth_serving = tokio::spawn(handle_client(server, chat.client()));
+            sloth.write_all(&cipher).await.unwrap();
+            sloth.write_all(&xor1(b"sloth\n")).await.unwrap();
+
+            let (alice, server) = tokio::io::duplex(1024);
+            tokio::spawn(handle_client(server, chat.client()));
+            let (alice_reader, mut alice) = tokio::io::split(alice);
+            alice.write_all(&cipher).await.unwrap();
+            alice.write_all(&xor1(b"alice\n")).await.unwrap();
+
+            // Wait until alice has joined after sloth, reading whatever she gets.
+            let mut alice_reader = tokio::io::BufReader::new(alice_reader);
+            let mut line = Vec::new();
+            while !xor1(&line).starts_with(b"* The room contains: sloth") {
+                line.clear();
+                alice_reader
+                    .read_until(xor1(b"\n")[0], &mut line)
+                    .await
+                    .unwrap();
+            }
+            tokio::spawn(async move {
+                let mut rest = Vec::new();
+                let _ = alice_reader.read_to_end(&mut rest).await;
+            });
+
+            // sloth never reads while alice keeps talking.
+            let mut sent = 0;
+            while !sloth_serving.is_finished() {
+                alice.write_all(&xor1(b"hello\n")).await.unwrap();
+                sent += 1;
+                assert!(sent < 10_000, "sloth was never disconnected");
+                tokio::task::yield_now().await;
+            }
+
+            assert_eq!(
+                sloth_serving.await.unwrap(),
+                Err(String::from("Client fell too far behind"))
+            );
+        });
+    }
 }
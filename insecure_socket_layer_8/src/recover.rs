@@ -0,0 +1,292 @@
+//! Known-plaintext recovery of the cipher behind a captured ISL stream.
+//!
+//! Tries every canonical sequence of up to `max_ops` operations and keeps the
+//! ciphers whose decoding of the capture fits the known plaintext.
+
+use crate::isl::cipher::simplify;
+use crate::isl::{Cipher, Operation};
+
+/// Bytes checked with the operations directly before a candidate is turned
+/// into a `Cipher`.
+const PREFILTER_LEN: usize = 16;
+
+/// What the decoded capture is known to look like.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Plaintext {
+    /// The capture decodes to exactly these bytes. A shorter plaintext only
+    /// constrains the start of the capture.
+    Exact(Vec<u8>),
+    /// The capture holds toy requests or responses, lines like
+    /// `10x toy car,15x dog on a string\n`. It must start at a line boundary
+    /// and may end mid-line.
+    ToyRequests,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ToyState {
+    CountStart,
+    Count,
+    Space,
+    NameStart,
+    Name,
+}
+
+impl ToyState {
+    fn next(self, byte: u8) -> Option<ToyState> {
+        let name_byte = (0x20..0x7f).contains(&byte) && byte != b',';
+
+        match self {
+            ToyState::CountStart if byte.is_ascii_digit() => Some(ToyState::Count),
+            ToyState::Count if byte.is_ascii_digit() => Some(ToyState::Count),
+            ToyState::Count if byte == b'x' => Some(ToyState::Space),
+            ToyState::Space if byte == b' ' => Some(ToyState::NameStart),
+            ToyState::NameStart if name_byte => Some(ToyState::Name),
+            ToyState::Name if byte == b',' || byte == b'\n' => Some(ToyState::CountStart),
+            ToyState::Name if name_byte => Some(ToyState::Name),
+            _ => None,
+        }
+    }
+}
+
+impl Plaintext {
+    /// Whether `decoded` bytes from the start of the capture fit.
+    fn accepts(&self, decoded: impl Iterator<Item = u8>) -> bool {
+        match self {
+            Plaintext::Exact(expected) => decoded.zip(expected).all(|(a, b)| a == *b),
+            Plaintext::ToyRequests => {
+                let mut state = ToyState::CountStart;
+                for byte in decoded {
+                    match state.next(byte) {
+                        Some(next) => state = next,
+                        None => return false,
+                    }
+                }
+                true
+            }
+        }
+    }
+}
+
+/// Every operation sequence shape of up to `max_ops` operations, with
+/// placeholder arguments, that doesn't simplify into a shorter one.
+fn shapes(max_ops: usize) -> Vec<Vec<Operation>> {
+    let kinds = [
+        Operation::ReverseBits,
+        Operation::Xor(1),
+        Operation::Xorpos,
+        Operation::Add(1),
+        Operation::Addpos,
+    ];
+
+    let mut shapes: Vec<Vec<Operation>> = vec![Vec::new()];
+    let mut all = Vec::new();
+    for _ in 0..max_ops {
+        shapes = shapes
+            .iter()
+            .flat_map(|shape| {
+                kinds.iter().map(move |kind| {
+                    let mut shape = shape.clone();
+                    shape.push(*kind);
+                    shape
+                })
+            })
+            .filter(|shape| simplify(shape).len() == shape.len())
+            .collect();
+        all.extend(shapes.iter().cloned());
+    }
+
+    all
+}
+
+/// Sets the argument of every `Xor` and `Add` in `ops` to the next value,
+/// odometer style over 1..=255. Returns false once all values were visited.
+fn next_arguments(ops: &mut [Operation]) -> bool {
+    for op in ops.iter_mut().rev() {
+        let arg = match op {
+            Operation::Xor(n) | Operation::Add(n) => n,
+            _ => continue,
+        };
+
+        if *arg == 255 {
+            *arg = 1;
+        } else {
+            *arg += 1;
+            return true;
+        }
+    }
+
+    false
+}
+
+/// Decodes the first `PREFILTER_LEN` bytes with the operations directly,
+/// then checks survivors against the whole capture with a real `Cipher`.
+fn check(
+    capture: &[u8],
+    offset: usize,
+    plaintext: &Plaintext,
+    ops: &[Operation],
+) -> Option<Cipher> {
+    let decoded = capture
+        .iter()
+        .take(PREFILTER_LEN)
+        .enumerate()
+        .map(|(i, byte)| {
+            ops.iter()
+                .rev()
+                .fold(*byte, |byte, op| op.decode(byte, offset + i))
+        });
+    if !plaintext.accepts(decoded) {
+        return None;
+    }
+
+    let cipher = Cipher::builder().operations(ops.iter().copied()).build();
+    let decoded = capture
+        .iter()
+        .enumerate()
+        .map(|(i, byte)| cipher.decode(*byte, offset + i));
+
+    if !cipher.is_no_op() && plaintext.accepts(decoded) {
+        Some(cipher)
+    } else {
+        None
+    }
+}
+
+impl Plaintext {
+    /// Bytes the capture may decode to at its start.
+    fn first_bytes(&self) -> Vec<u8> {
+        match self {
+            Plaintext::Exact(expected) => expected.iter().take(1).copied().collect(),
+            Plaintext::ToyRequests => (b'0'..=b'9').collect(),
+        }
+    }
+}
+
+/// Lists every cipher of up to `max_ops` operations that decodes `capture`
+/// into something `plaintext` accepts. `offset` is the stream position of
+/// the first captured byte. No-op ciphers are left out, servers reject them.
+pub fn recover(
+    capture: &[u8],
+    offset: usize,
+    plaintext: &Plaintext,
+    max_ops: usize,
+) -> Vec<Cipher> {
+    let Some(first) = capture.first() else {
+        return Vec::new();
+    };
+    let first_bytes = plaintext.first_bytes();
+    let mut found = Vec::new();
+
+    for mut ops in shapes(max_ops) {
+        loop {
+            // The first operation is undone last, so when it takes an
+            // argument only the values that decode the first byte into
+            // something acceptable are worth trying.
+            let rest = &ops[1..];
+            let middle = rest
+                .iter()
+                .rev()
+                .fold(*first, |byte, op| op.decode(byte, offset));
+            let candidates: Vec<Operation> = match ops[0] {
+                Operation::Xor(_) => first_bytes
+                    .iter()
+                    .map(|plain| Operation::Xor(middle ^ plain))
+                    .collect(),
+                Operation::Add(_) => first_bytes
+                    .iter()
+                    .map(|plain| Operation::Add(middle.wrapping_sub(*plain)))
+                    .collect(),
+                op => vec![op],
+            };
+
+            for candidate in candidates {
+                if matches!(candidate, Operation::Xor(0) | Operation::Add(0)) {
+                    continue;
+                }
+
+                ops[0] = candidate;
+                found.extend(check(capture, offset, plaintext, &ops));
+            }
+
+            if !next_arguments(&mut ops[1..]) {
+                break;
+            }
+        }
+    }
+
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(cipher: &Cipher, plaintext: &[u8], offset: usize) -> Vec<u8> {
+        plaintext
+            .iter()
+            .enumerate()
+            .map(|(i, byte)| cipher.encode(*byte, offset + i))
+            .collect()
+    }
+
+    #[test]
+    fn toy_requests() {
+        let plaintext = Plaintext::ToyRequests;
+        for line in [
+            "4x dog,5x car\n3x rat",
+            "10x toy car,15x dog on a string\n",
+            "1",
+        ] {
+            assert!(plaintext.accepts(line.bytes()), "{:?}", line);
+        }
+        for line in [
+            "x dog\n",
+            "4xdog\n",
+            "4x \n",
+            "4x dog,,5x car\n",
+            "4x d\x00g\n",
+        ] {
+            assert!(!plaintext.accepts(line.bytes()), "{:?}", line);
+        }
+    }
+
+    #[test]
+    fn recovers_example_session() {
+        let cipher = Cipher::builder().xor(123).addpos().reverse_bits().build();
+        let requests = b"4x dog,5x car\n3x rat,2x cat\n";
+        let capture = encode(&cipher, requests, 0);
+
+        let found = recover(&capture, 0, &Plaintext::Exact(requests.to_vec()), 3);
+        let specs: Vec<String> = found.iter().map(|c| c.to_string()).collect();
+        assert!(specs.contains(&cipher.to_string()), "{:?}", specs);
+
+        // Every reported cipher really decodes the capture.
+        for cipher in &found {
+            let decoded: Vec<u8> = capture
+                .iter()
+                .enumerate()
+                .map(|(i, byte)| cipher.decode(*byte, i))
+                .collect();
+            assert_eq!(decoded, requests);
+        }
+    }
+
+    #[test]
+    fn recovers_from_toy_grammar() {
+        let cipher = Cipher::builder().xorpos().add(42).build();
+        let requests = b"10x toy car,15x dog on a string,4x inflatable motorcycle\n";
+        let capture = encode(&cipher, requests, 5);
+
+        let found = recover(&capture, 5, &Plaintext::ToyRequests, 2);
+        let specs: Vec<String> = found.iter().map(|c| c.to_string()).collect();
+        assert!(
+            specs.contains(&String::from("xorpos,add(42)")),
+            "{:?}",
+            specs
+        );
+
+        // Decoding at the wrong offset doesn't look like toys.
+        let found = recover(&capture, 0, &Plaintext::ToyRequests, 2);
+        assert!(found.iter().all(|c| c.to_string() != "xorpos,add(42)"));
+    }
+}
@@ -0,0 +1,234 @@
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Toy {
+    pub count: u64,
+    pub name: String,
+}
+
+impl fmt::Display for Toy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}x {}", self.count, self.name)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ToyErrorKind {
+    /// No digits where the count should start.
+    MissingCount,
+    /// The count doesn't fit in a u64.
+    CountTooLarge,
+    /// The count isn't followed by `x`.
+    MissingX,
+    /// The `x` isn't followed by a space.
+    MissingSpace,
+    /// Nothing but whitespace after `x `.
+    EmptyName,
+}
+
+impl fmt::Display for ToyErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            ToyErrorKind::MissingCount => "expected a count",
+            ToyErrorKind::CountTooLarge => "count is too large",
+            ToyErrorKind::MissingX => "expected 'x' after the count",
+            ToyErrorKind::MissingSpace => "expected a space after 'x'",
+            ToyErrorKind::EmptyName => "empty toy name",
+        };
+        write!(f, "{}", message)
+    }
+}
+
+/// Why a toy list failed to parse. `field` is the index of the
+/// comma-separated entry and `column` the 1-based byte column in the line
+/// where the problem starts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParseToysError {
+    pub field: usize,
+    pub column: usize,
+    pub kind: ToyErrorKind,
+}
+
+impl fmt::Display for ParseToysError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Invalid toy {} at column {}: {}",
+            self.field, self.column, self.kind
+        )
+    }
+}
+
+/// Parses a single `10x toy car` entry that starts at byte `start` of the line.
+fn parse_toy(entry: &str, field: usize, start: usize) -> Result<Toy, ParseToysError> {
+    let error = |offset: usize, kind| ParseToysError {
+        field,
+        column: start + offset + 1,
+        kind,
+    };
+
+    let digits = entry.bytes().take_while(u8::is_ascii_digit).count();
+    if digits == 0 {
+        return Err(error(0, ToyErrorKind::MissingCount));
+    }
+    let count = entry[..digits]
+        .parse::<u64>()
+        .map_err(|_| error(0, ToyErrorKind::CountTooLarge))?;
+
+    let rest = entry[digits..]
+        .strip_prefix('x')
+        .ok_or(error(digits, ToyErrorKind::MissingX))?;
+    let name = rest
+        .strip_prefix(' ')
+        .ok_or(error(digits + 1, ToyErrorKind::MissingSpace))?;
+
+    if name.trim().is_empty() {
+        return Err(error(digits + 2, ToyErrorKind::EmptyName));
+    }
+
+    Ok(Toy {
+        count,
+        name: String::from(name),
+    })
+}
+
+/// Parses a toy list like `10x toy car,15x dog on a string\n`. The trailing
+/// newline is optional.
+pub fn parse_toys(line: &str) -> Result<Vec<Toy>, ParseToysError> {
+    let line = line.strip_suffix('\n').unwrap_or(line);
+
+    let mut toys = Vec::new();
+    let mut start = 0;
+    for (field, entry) in line.split(',').enumerate() {
+        toys.push(parse_toy(entry, field, start)?);
+        start += entry.len() + 1;
+    }
+
+    Ok(toys)
+}
+
+/// The toy with the highest count, the first one listed on ties.
+pub fn most_toys(toys: &[Toy]) -> Option<&Toy> {
+    toys.iter()
+        .fold(None, |best: Option<&Toy>, toy| match best {
+            Some(best) if best.count >= toy.count => Some(best),
+            _ => Some(toy),
+        })
+}
+
+pub fn get_most_toys(toys: String) -> Result<String, String> {
+    let toys = parse_toys(&toys).map_err(|e| e.to_string())?;
+    let most_toys = most_toys(&toys).ok_or("Empty toy list")?;
+
+    Ok(format!("{}\n", most_toys))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+
+    #[test]
+    fn basic_toys() {
+        let s = "1x dog,3x car,2x rat\n";
+        assert_eq!(get_most_toys(String::from(s)).unwrap(), "3x car\n");
+    }
+
+    #[test]
+    fn invalid_toys() {
+        let s = "1x dog,3 car,2x rat\n";
+        assert_eq!(
+            get_most_toys(String::from(s)),
+            Err(String::from(
+                "Invalid toy 1 at column 9: expected 'x' after the count"
+            ))
+        );
+
+        let s = "\n";
+        assert_eq!(
+            get_most_toys(String::from(s)),
+            Err(String::from("Invalid toy 0 at column 1: expected a count"))
+        );
+    }
+
+    #[test]
+    fn parse_errors() {
+        let cases = [
+            ("x dog", 0, 1, ToyErrorKind::MissingCount),
+            ("1x dog,,2x cat", 1, 8, ToyErrorKind::MissingCount),
+            ("1x dog,-2x cat", 1, 8, ToyErrorKind::MissingCount),
+            (
+                "99999999999999999999x dog",
+                0,
+                1,
+                ToyErrorKind::CountTooLarge,
+            ),
+            ("1x dog,2 cat", 1, 9, ToyErrorKind::MissingX),
+            ("1xdog", 0, 3, ToyErrorKind::MissingSpace),
+            ("1x dog,2x ", 1, 11, ToyErrorKind::EmptyName),
+            ("1x    \n", 0, 4, ToyErrorKind::EmptyName),
+        ];
+
+        for (line, field, column, kind) in cases {
+            assert_eq!(
+                parse_toys(line),
+                Err(ParseToysError {
+                    field,
+                    column,
+                    kind
+                }),
+                "{:?}",
+                line
+            );
+        }
+    }
+
+    #[test]
+    fn names_with_x_and_large_counts() {
+        let toys = parse_toys("18446744073709551615x xbox,5x x-ray specs\n").unwrap();
+        assert_eq!(
+            toys,
+            [
+                Toy {
+                    count: u64::MAX,
+                    name: String::from("xbox")
+                },
+                Toy {
+                    count: 5,
+                    name: String::from("x-ray specs")
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn first_wins_ties() {
+        let s = "3x dog,5x car,5x rat,1x cat\n";
+        assert_eq!(get_most_toys(String::from(s)).unwrap(), "5x car\n");
+    }
+
+    fn toy() -> impl Strategy<Value = Toy> {
+        (any::<u64>(), "[a-zA-Z0-9x ][a-zA-Z0-9x -]{0,20}")
+            .prop_filter("name must not be blank", |(_, name)| {
+                !name.trim().is_empty()
+            })
+            .prop_map(|(count, name)| Toy { count, name })
+    }
+
+    proptest! {
+        #[test]
+        fn round_trip(toys in prop::collection::vec(toy(), 1..20)) {
+            let line = toys
+                .iter()
+                .map(|toy| toy.to_string())
+                .collect::<Vec<String>>()
+                .join(",");
+
+            prop_assert_eq!(&parse_toys(&format!("{line}\n")).unwrap(), &toys);
+
+            let most = toys.iter().map(|toy| toy.count).max().unwrap();
+            let first = toys.iter().find(|toy| toy.count == most).unwrap();
+            prop_assert_eq!(get_most_toys(line).unwrap(), format!("{first}\n"));
+        }
+    }
+}
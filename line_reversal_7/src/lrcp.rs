@@ -0,0 +1,657 @@
+mod packet;
+
+pub use packet::Packet;
+
+use packet::{escaped_len, MAX_PACKET};
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio::net::{ToSocketAddrs, UdpSocket};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::time::{self, Instant};
+
+/// Unacknowledged data is sent again after this long.
+const RETRANSMISSION_TIMEOUT: Duration = Duration::from_secs(3);
+/// A session with unacknowledged data is dropped if the peer is silent this long.
+const SESSION_EXPIRY_TIMEOUT: Duration = Duration::from_secs(60);
+/// How often sessions are checked for retransmission and expiry.
+const TIMER_TICK: Duration = Duration::from_millis(100);
+
+enum Command {
+    Write(Vec<u8>),
+    Close,
+}
+
+/// How much of a session's written data the peer has acknowledged, shared
+/// between the driver and the session handle so `flush` can wait for it.
+#[derive(Default)]
+struct Progress {
+    acked: u32,
+    /// The driver dropped the session, nothing more will be acknowledged.
+    closed: bool,
+    waker: Option<Waker>,
+}
+
+impl Progress {
+    fn update(progress: &Mutex<Progress>, change: impl FnOnce(&mut Progress)) {
+        let mut progress = progress.lock().unwrap();
+        change(&mut progress);
+        if let Some(waker) = progress.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// Driver-side state of one session.
+struct SessionState {
+    addr: SocketAddr,
+    /// Bytes received in order so far.
+    received: u32,
+    incoming: UnboundedSender<Vec<u8>>,
+    /// Bytes acknowledged by the peer, `unacked` starts at this position.
+    acked: u32,
+    unacked: Vec<u8>,
+    progress: Arc<Mutex<Progress>>,
+    last_seen: Instant,
+    last_sent: Instant,
+}
+
+impl Drop for SessionState {
+    fn drop(&mut self) {
+        // However the session ends, a pending flush learns about it.
+        Progress::update(&self.progress, |progress| progress.closed = true);
+    }
+}
+
+/// Owns the socket and runs every session on it.
+struct Driver {
+    socket: UdpSocket,
+    sessions: HashMap<u32, SessionState>,
+    accepted: UnboundedSender<Session>,
+    commands_tx: UnboundedSender<(u32, Command)>,
+    commands: UnboundedReceiver<(u32, Command)>,
+}
+
+impl Driver {
+    async fn run(mut self) {
+        let mut buf = [0; MAX_PACKET];
+        let mut timer = time::interval(TIMER_TICK);
+
+        loop {
+            tokio::select! {
+                res = self.socket.recv_from(&mut buf) => {
+                    if let Ok((size, addr)) = res {
+                        if let Some(packet) = Packet::parse(&buf[..size]) {
+                            self.handle_packet(packet, addr).await;
+                        }
+                    }
+                }
+                Some((session, command)) = self.commands.recv() => {
+                    self.handle_command(session, command).await;
+                }
+                _ = timer.tick() => {
+                    self.check_timers().await;
+
+                    if self.accepted.is_closed() && self.sessions.is_empty() {
+                        return;
+                    }
+                }
+            }
+        }
+    }
+
+    async fn send(&self, packet: Packet, addr: SocketAddr) {
+        let _ = self.socket.send_to(&packet.to_bytes(), addr).await;
+    }
+
+    /// Sends `data` starting at `pos`, split so every packet fits the limit.
+    async fn send_data(&self, session: u32, mut pos: u32, mut data: &[u8], addr: SocketAddr) {
+        while !data.is_empty() {
+            // Header, data and the closing slash must stay below MAX_PACKET.
+            let header = format!("/data/{session}/{pos}/").len() + 1;
+            let mut len = 0;
+            let mut size = header;
+            while len < data.len() && size + escaped_len(data[len]) < MAX_PACKET {
+                size += escaped_len(data[len]);
+                len += 1;
+            }
+
+            let packet = Packet::Data {
+                session,
+                pos,
+                data: data[..len].to_vec(),
+            };
+            self.send(packet, addr).await;
+
+            pos += len as u32;
+            data = &data[len..];
+        }
+    }
+
+    async fn handle_packet(&mut self, packet: Packet, addr: SocketAddr) {
+        match packet {
+            Packet::Connect { session } => {
+                if self.sessions.contains_key(&session) || self.open(session, addr) {
+                    self.send(Packet::Ack { session, length: 0 }, addr).await;
+                } else {
+                    self.send(Packet::Close { session }, addr).await;
+                }
+            }
+            Packet::Data { session, pos, data } => {
+                let Some(state) = self.sessions.get_mut(&session) else {
+                    self.send(Packet::Close { session }, addr).await;
+                    return;
+                };
+                state.addr = addr;
+                state.last_seen = Instant::now();
+
+                // Keep whatever extends past what we already have.
+                let end = pos as u64 + data.len() as u64;
+                if pos <= state.received && end > state.received as u64 {
+                    let new = data[(state.received - pos) as usize..].to_vec();
+                    state.received += new.len() as u32;
+                    let _ = state.incoming.send(new);
+                }
+
+                let length = state.received;
+                self.send(Packet::Ack { session, length }, addr).await;
+            }
+            Packet::Ack { session, length } => {
+                let Some(state) = self.sessions.get_mut(&session) else {
+                    self.send(Packet::Close { session }, addr).await;
+                    return;
+                };
+                state.addr = addr;
+                state.last_seen = Instant::now();
+
+                if length <= state.acked {
+                    return;
+                }
+
+                let sent = state.acked as u64 + state.unacked.len() as u64;
+                if length as u64 > sent {
+                    // The peer acknowledged data we never sent.
+                    self.close(session).await;
+                    return;
+                }
+
+                state.unacked.drain(..(length - state.acked) as usize);
+                state.acked = length;
+                Progress::update(&state.progress, |progress| progress.acked = length);
+
+                if !state.unacked.is_empty() {
+                    state.last_sent = Instant::now();
+                    let unacked = state.unacked.clone();
+                    self.send_data(session, length, &unacked, addr).await;
+                }
+            }
+            Packet::Close { session } => {
+                self.sessions.remove(&session);
+                self.send(Packet::Close { session }, addr).await;
+            }
+        }
+    }
+
+    async fn handle_command(&mut self, session: u32, command: Command) {
+        match command {
+            Command::Write(data) => {
+                let Some(state) = self.sessions.get_mut(&session) else {
+                    return;
+                };
+
+                let pos = state.acked + state.unacked.len() as u32;
+                state.unacked.extend_from_slice(&data);
+                state.last_sent = Instant::now();
+                let addr = state.addr;
+                self.send_data(session, pos, &data, addr).await;
+            }
+            Command::Close => self.close(session).await,
+        }
+    }
+
+    async fn check_timers(&mut self) {
+        let now = Instant::now();
+        let mut expired = Vec::new();
+        let mut retransmit = Vec::new();
+
+        for (session, state) in self.sessions.iter_mut() {
+            if state.unacked.is_empty() {
+                continue;
+            }
+
+            if now - state.last_seen >= SESSION_EXPIRY_TIMEOUT {
+                expired.push(*session);
+            } else if now - state.last_sent >= RETRANSMISSION_TIMEOUT {
+                state.last_sent = now;
+                retransmit.push((*session, state.acked, state.unacked.clone(), state.addr));
+            }
+        }
+
+        for session in expired {
+            self.sessions.remove(&session);
+        }
+
+        for (session, pos, data, addr) in retransmit {
+            self.send_data(session, pos, &data, addr).await;
+        }
+    }
+
+    /// Starts a session and hands it to the listener. Returns false if the
+    /// listener is gone and nobody would ever accept it.
+    fn open(&mut self, session: u32, addr: SocketAddr) -> bool {
+        let (incoming_tx, incoming) = mpsc::unbounded_channel();
+        let progress = Arc::new(Mutex::new(Progress::default()));
+
+        let handle = Session {
+            id: session,
+            peer: addr,
+            incoming,
+            buffer: Vec::new(),
+            written: 0,
+            progress: progress.clone(),
+            commands: self.commands_tx.clone(),
+        };
+        if self.accepted.send(handle).is_err() {
+            return false;
+        }
+
+        let now = Instant::now();
+        self.sessions.insert(
+            session,
+            SessionState {
+                addr,
+                received: 0,
+                incoming: incoming_tx,
+                acked: 0,
+                unacked: Vec::new(),
+                progress,
+                last_seen: now,
+                last_sent: now,
+            },
+        );
+        true
+    }
+
+    async fn close(&mut self, session: u32) {
+        if let Some(state) = self.sessions.remove(&session) {
+            self.send(Packet::Close { session }, state.addr).await;
+        }
+    }
+}
+
+/// Accepts LRCP sessions on a UDP socket.
+pub struct Listener {
+    local_addr: SocketAddr,
+    accepted: UnboundedReceiver<Session>,
+}
+
+impl Listener {
+    pub async fn bind<A: ToSocketAddrs>(addr: A) -> io::Result<Listener> {
+        let socket = UdpSocket::bind(addr).await?;
+        let local_addr = socket.local_addr()?;
+        let (accepted_tx, accepted) = mpsc::unbounded_channel();
+        let (commands_tx, commands) = mpsc::unbounded_channel();
+
+        let driver = Driver {
+            socket,
+            sessions: HashMap::new(),
+            accepted: accepted_tx,
+            commands_tx,
+            commands,
+        };
+        tokio::spawn(driver.run());
+
+        Ok(Listener {
+            local_addr,
+            accepted,
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub async fn accept(&mut self) -> io::Result<Session> {
+        self.accepted
+            .recv()
+            .await
+            .ok_or(io::Error::from(io::ErrorKind::BrokenPipe))
+    }
+}
+
+/// A single LRCP session, read and written like a TCP stream.
+///
+/// Reads return end of file once the peer closes the session or it expires.
+///
+/// Writes return as soon as the data is queued. Flushing waits until the peer
+/// has acknowledged everything written so far, and fails if the session is
+/// closed or expires first, since unacknowledged data is then lost. Shutting
+/// down flushes and then closes the session. Dropping it closes it at once,
+/// discarding anything not yet acknowledged.
+pub struct Session {
+    id: u32,
+    peer: SocketAddr,
+    incoming: UnboundedReceiver<Vec<u8>>,
+    buffer: Vec<u8>,
+    /// Bytes handed to the driver so far.
+    written: u32,
+    progress: Arc<Mutex<Progress>>,
+    commands: UnboundedSender<(u32, Command)>,
+}
+
+impl Session {
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer
+    }
+}
+
+impl AsyncRead for Session {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+
+        while this.buffer.is_empty() {
+            match this.incoming.poll_recv(cx) {
+                Poll::Ready(Some(data)) => this.buffer = data,
+                Poll::Ready(None) => return Poll::Ready(Ok(())),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+
+        let len = this.buffer.len().min(buf.remaining());
+        buf.put_slice(&this.buffer[..len]);
+        this.buffer.drain(..len);
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncWrite for Session {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if self.progress.lock().unwrap().closed {
+            return Poll::Ready(Err(io::Error::from(io::ErrorKind::BrokenPipe)));
+        }
+
+        match self.commands.send((self.id, Command::Write(buf.to_vec()))) {
+            Ok(()) => {
+                self.written += buf.len() as u32;
+                Poll::Ready(Ok(buf.len()))
+            }
+            Err(_) => Poll::Ready(Err(io::Error::from(io::ErrorKind::BrokenPipe))),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mut progress = self.progress.lock().unwrap();
+
+        if progress.acked >= self.written {
+            Poll::Ready(Ok(()))
+        } else if progress.closed {
+            Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "Session closed before the peer acknowledged every byte",
+            )))
+        } else {
+            progress.waker = Some(cx.waker().clone());
+            Poll::Pending
+        }
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let flushed = match self.as_mut().poll_flush(cx) {
+            Poll::Ready(flushed) => flushed,
+            Poll::Pending => return Poll::Pending,
+        };
+
+        let _ = self.commands.send((self.id, Command::Close));
+        Poll::Ready(flushed)
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        let _ = self.commands.send((self.id, Command::Close));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::runtime::Runtime;
+
+    async fn recv_packet(socket: &UdpSocket) -> Packet {
+        let mut buf = [0; MAX_PACKET];
+        let size = time::timeout(Duration::from_secs(5), socket.recv(&mut buf))
+            .await
+            .unwrap()
+            .unwrap();
+        Packet::parse(&buf[..size]).unwrap()
+    }
+
+    async fn send_packet(socket: &UdpSocket, packet: Packet) {
+        socket.send(&packet.to_bytes()).await.unwrap();
+    }
+
+    #[test]
+    fn session_read_write() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let mut listener = Listener::bind("127.0.0.1:0").await.unwrap();
+            let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            client.connect(listener.local_addr()).await.unwrap();
+
+            send_packet(&client, Packet::Connect { session: 7 }).await;
+            assert_eq!(
+                recv_packet(&client).await,
+                Packet::Ack {
+                    session: 7,
+                    length: 0
+                }
+            );
+            let mut session = listener.accept().await.unwrap();
+            assert_eq!(session.id(), 7);
+
+            // Out of order data is not acknowledged.
+            send_packet(
+                &client,
+                Packet::Data {
+                    session: 7,
+                    pos: 3,
+                    data: b"lo/".to_vec(),
+                },
+            )
+            .await;
+            assert_eq!(
+                recv_packet(&client).await,
+                Packet::Ack {
+                    session: 7,
+                    length: 0
+                }
+            );
+
+            send_packet(
+                &client,
+                Packet::Data {
+                    session: 7,
+                    pos: 0,
+                    data: b"hel".to_vec(),
+                },
+            )
+            .await;
+            assert_eq!(
+                recv_packet(&client).await,
+                Packet::Ack {
+                    session: 7,
+                    length: 3
+                }
+            );
+            send_packet(
+                &client,
+                Packet::Data {
+                    session: 7,
+                    pos: 0,
+                    data: b"hello/".to_vec(),
+                },
+            )
+            .await;
+            assert_eq!(
+                recv_packet(&client).await,
+                Packet::Ack {
+                    session: 7,
+                    length: 6
+                }
+            );
+
+            let mut buf = [0; 6];
+            session.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"hello/");
+
+            session.write_all(b"olleh\n").await.unwrap();
+            assert_eq!(
+                recv_packet(&client).await,
+                Packet::Data {
+                    session: 7,
+                    pos: 0,
+                    data: b"olleh\n".to_vec()
+                }
+            );
+            send_packet(
+                &client,
+                Packet::Ack {
+                    session: 7,
+                    length: 6,
+                },
+            )
+            .await;
+            session.flush().await.unwrap();
+
+            send_packet(&client, Packet::Close { session: 7 }).await;
+            assert_eq!(recv_packet(&client).await, Packet::Close { session: 7 });
+            assert_eq!(session.read(&mut buf).await.unwrap(), 0);
+        });
+    }
+
+    #[test]
+    fn unacknowledged_writes_fail_on_close() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let mut listener = Listener::bind("127.0.0.1:0").await.unwrap();
+            let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            client.connect(listener.local_addr()).await.unwrap();
+
+            send_packet(&client, Packet::Connect { session: 3 }).await;
+            recv_packet(&client).await;
+            let mut session = listener.accept().await.unwrap();
+
+            session.write_all(b"hello\n").await.unwrap();
+            recv_packet(&client).await;
+
+            // The peer goes away without acknowledging anything.
+            send_packet(&client, Packet::Close { session: 3 }).await;
+            let err = session.flush().await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::ConnectionAborted);
+            assert!(session.write_all(b"more\n").await.is_err());
+        });
+    }
+
+    #[test]
+    fn unknown_session_is_closed() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let listener = Listener::bind("127.0.0.1:0").await.unwrap();
+            let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            client.connect(listener.local_addr()).await.unwrap();
+
+            send_packet(
+                &client,
+                Packet::Ack {
+                    session: 9,
+                    length: 0,
+                },
+            )
+            .await;
+            assert_eq!(recv_packet(&client).await, Packet::Close { session: 9 });
+        });
+    }
+
+    #[test]
+    fn connect_is_refused_once_the_listener_is_gone() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let mut listener = Listener::bind("127.0.0.1:0").await.unwrap();
+            let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            client.connect(listener.local_addr()).await.unwrap();
+
+            // An open session keeps the driver running.
+            send_packet(&client, Packet::Connect { session: 1 }).await;
+            recv_packet(&client).await;
+            let _session = listener.accept().await.unwrap();
+            drop(listener);
+
+            send_packet(&client, Packet::Connect { session: 2 }).await;
+            assert_eq!(recv_packet(&client).await, Packet::Close { session: 2 });
+        });
+    }
+
+    #[test]
+    fn large_writes_are_split() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let mut listener = Listener::bind("127.0.0.1:0").await.unwrap();
+            let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            client.connect(listener.local_addr()).await.unwrap();
+
+            send_packet(&client, Packet::Connect { session: 1 }).await;
+            recv_packet(&client).await;
+            let mut session = listener.accept().await.unwrap();
+
+            let data: Vec<u8> = (0..3000).map(|i| b"ab/\\"[i % 4]).collect();
+            session.write_all(&data).await.unwrap();
+
+            let mut received = Vec::new();
+            while received.len() < data.len() {
+                let mut buf = [0; MAX_PACKET];
+                let size = client.recv(&mut buf).await.unwrap();
+                assert!(size < MAX_PACKET);
+                match Packet::parse(&buf[..size]).unwrap() {
+                    Packet::Data { pos, data, .. } => {
+                        assert_eq!(pos as usize, received.len());
+                        received.extend(data);
+                    }
+                    other => panic!("unexpected {:?}", other),
+                }
+            }
+            assert_eq!(received, data);
+
+            // A partial ack makes the rest go out again.
+            send_packet(
+                &client,
+                Packet::Ack {
+                    session: 1,
+                    length: 2000,
+                },
+            )
+            .await;
+            match recv_packet(&client).await {
+                Packet::Data { pos, .. } => assert_eq!(pos, 2000),
+                other => panic!("unexpected {:?}", other),
+            }
+        });
+    }
+}
@@ -0,0 +1,205 @@
+/// Packets must be smaller than this many bytes.
+pub const MAX_PACKET: usize = 1000;
+
+/// Numeric fields must be smaller than this.
+const MAX_NUMBER: u32 = 2147483648;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Packet {
+    Connect {
+        session: u32,
+    },
+    Data {
+        session: u32,
+        pos: u32,
+        data: Vec<u8>,
+    },
+    Ack {
+        session: u32,
+        length: u32,
+    },
+    Close {
+        session: u32,
+    },
+}
+
+/// Escapes `/` and `\` in packet data.
+pub fn escape(data: &[u8]) -> Vec<u8> {
+    let mut escaped = Vec::with_capacity(data.len());
+    for byte in data {
+        if *byte == b'/' || *byte == b'\\' {
+            escaped.push(b'\\');
+        }
+        escaped.push(*byte);
+    }
+    escaped
+}
+
+/// Length of `byte` once escaped.
+pub fn escaped_len(byte: u8) -> usize {
+    if byte == b'/' || byte == b'\\' {
+        2
+    } else {
+        1
+    }
+}
+
+/// Splits a packet body into unescaped fields.
+///
+/// Returns `None` if the packet is not wrapped in slashes or contains a
+/// backslash that does not escape `/` or `\`.
+fn split_fields(packet: &[u8]) -> Option<Vec<Vec<u8>>> {
+    let body = packet.strip_prefix(b"/")?;
+    let mut fields = Vec::new();
+    let mut field = Vec::new();
+    let mut bytes = body.iter();
+    let mut terminated = false;
+
+    while let Some(byte) = bytes.next() {
+        terminated = false;
+        match byte {
+            b'\\' => match bytes.next() {
+                Some(escaped @ (b'/' | b'\\')) => field.push(*escaped),
+                _ => return None,
+            },
+            b'/' => {
+                fields.push(std::mem::take(&mut field));
+                terminated = true;
+            }
+            other => field.push(*other),
+        }
+    }
+
+    if terminated {
+        Some(fields)
+    } else {
+        None
+    }
+}
+
+fn parse_number(field: &[u8]) -> Option<u32> {
+    if field.is_empty() || !field.iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+
+    let n: u32 = std::str::from_utf8(field).ok()?.parse().ok()?;
+    if n < MAX_NUMBER {
+        Some(n)
+    } else {
+        None
+    }
+}
+
+impl Packet {
+    /// Parses a packet, returning `None` for anything invalid.
+    pub fn parse(packet: &[u8]) -> Option<Packet> {
+        if packet.len() >= MAX_PACKET {
+            return None;
+        }
+
+        let fields = split_fields(packet)?;
+        let (kind, args) = fields.split_first()?;
+
+        match (kind.as_slice(), args) {
+            (b"connect", [session]) => Some(Packet::Connect {
+                session: parse_number(session)?,
+            }),
+            (b"data", [session, pos, data]) => Some(Packet::Data {
+                session: parse_number(session)?,
+                pos: parse_number(pos)?,
+                data: data.clone(),
+            }),
+            (b"ack", [session, length]) => Some(Packet::Ack {
+                session: parse_number(session)?,
+                length: parse_number(length)?,
+            }),
+            (b"close", [session]) => Some(Packet::Close {
+                session: parse_number(session)?,
+            }),
+            _ => None,
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        match self {
+            Packet::Connect { session } => format!("/connect/{session}/").into_bytes(),
+            Packet::Data { session, pos, data } => {
+                let mut bytes = format!("/data/{session}/{pos}/").into_bytes();
+                bytes.extend(escape(data));
+                bytes.push(b'/');
+                bytes
+            }
+            Packet::Ack { session, length } => format!("/ack/{session}/{length}/").into_bytes(),
+            Packet::Close { session } => format!("/close/{session}/").into_bytes(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_packets() {
+        assert_eq!(
+            Packet::parse(b"/connect/1234567/"),
+            Some(Packet::Connect { session: 1234567 })
+        );
+        assert_eq!(
+            Packet::parse(b"/data/1234567/0/hello\n/"),
+            Some(Packet::Data {
+                session: 1234567,
+                pos: 0,
+                data: b"hello\n".to_vec()
+            })
+        );
+        assert_eq!(
+            Packet::parse(b"/ack/1234567/6/"),
+            Some(Packet::Ack {
+                session: 1234567,
+                length: 6
+            })
+        );
+        assert_eq!(
+            Packet::parse(b"/close/1234567/"),
+            Some(Packet::Close { session: 1234567 })
+        );
+    }
+
+    #[test]
+    fn escaping() {
+        let packet = Packet::Data {
+            session: 1,
+            pos: 0,
+            data: b"foo/bar\\baz".to_vec(),
+        };
+        let bytes = packet.to_bytes();
+
+        assert_eq!(bytes, b"/data/1/0/foo\\/bar\\\\baz/");
+        assert_eq!(Packet::parse(&bytes), Some(packet));
+    }
+
+    #[test]
+    fn reject_invalid() {
+        for packet in [
+            &b""[..],
+            b"/",
+            b"connect/1/",
+            b"/connect/1",
+            b"/connect/1/2/",
+            b"/connect/-1/",
+            b"/connect/2147483648/",
+            b"/ack/1/",
+            b"/data/1/0/foo/bar/",
+            b"/data/1/0/foo\\bar/",
+            b"/hello/1/",
+        ] {
+            assert_eq!(Packet::parse(packet), None, "{:?}", packet);
+        }
+
+        let mut long = b"/data/1/0/".to_vec();
+        long.resize(MAX_PACKET - 1, b'a');
+        long.push(b'/');
+        assert_eq!(Packet::parse(&long), None);
+    }
+}
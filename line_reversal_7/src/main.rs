@@ -0,0 +1,82 @@
+mod lrcp;
+
+use lrcp::{Listener, Session};
+use std::io;
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
+
+fn reverse_line(line: &str) -> String {
+    let mut reversed: String = line.chars().rev().collect();
+    reversed.push('\n');
+    reversed
+}
+
+async fn handle_client<S>(session: S) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (reader, mut writer) = tokio::io::split(session);
+    let mut lines = BufReader::new(reader).lines();
+
+    while let Some(line) = lines.next_line().await? {
+        writer.write_all(reverse_line(&line).as_bytes()).await?;
+    }
+
+    Ok(())
+}
+
+async fn handle_session(session: Session) {
+    let id = session.id();
+    println!("Session {id} from {}", session.peer_addr());
+
+    match handle_client(session).await {
+        Ok(_) => println!("Session {id} closed"),
+        Err(e) => println!("Session {id}: {e}"),
+    }
+}
+
+#[tokio::main]
+async fn main() -> io::Result<()> {
+    let mut listener = Listener::bind("0.0.0.0:10000").await?;
+    println!("Listening on {}", listener.local_addr());
+
+    loop {
+        let session = listener.accept().await?;
+        tokio::spawn(async move {
+            handle_session(session).await;
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncReadExt;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn reverse() {
+        assert_eq!(reverse_line("hello"), "olleh\n");
+        assert_eq!(reverse_line(""), "\n");
+    }
+
+    #[test]
+    fn handle_client_reverses_lines() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (mut client, server) = tokio::io::duplex(64);
+
+            tokio::spawn(async move {
+                handle_client(server).await.unwrap();
+            });
+
+            client
+                .write_all(b"hello\nNow is the time for all good men\n")
+                .await
+                .unwrap();
+
+            let mut buf = [0u8; 39];
+            client.read_exact(&mut buf).await.unwrap();
+            assert_eq!(&buf, b"olleh\nnem doog lla rof emit eht si woN\n");
+        });
+    }
+}
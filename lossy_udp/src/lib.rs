@@ -0,0 +1,488 @@
+//! In-process UDP proxy that drops, duplicates, reorders, delays and truncates
+//! packets, for testing UDP services against a bad network.
+//!
+//! The proxy runs on its own threads with plain std sockets, so it works the
+//! same in front of blocking and tokio servers. Point the client at
+//! [`LossyProxy::local_addr`] instead of the server. Every client address gets
+//! its own upstream socket, so the server still sees one peer per client.
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// How badly the network behaves. Probabilities are between 0 and 1 and apply
+/// to packets in both directions.
+#[derive(Debug, Clone)]
+pub struct Impairments {
+    /// Chance a packet is dropped.
+    pub loss: f64,
+    /// Chance a packet is delivered twice.
+    pub duplicate: f64,
+    /// Chance a packet is held back by `reorder_delay` so later ones overtake it.
+    pub reorder: f64,
+    pub reorder_delay: Duration,
+    /// Chance a packet is cut short at a random length.
+    pub truncate: f64,
+    /// Delay added to every packet.
+    pub delay: Duration,
+    /// Random extra delay of up to this much on every packet.
+    pub jitter: Duration,
+    /// Seed for every random decision. Each client and direction draws from its
+    /// own generator, so the same seed and traffic give the same faults however
+    /// the two directions interleave.
+    pub seed: u64,
+}
+
+impl Default for Impairments {
+    fn default() -> Impairments {
+        Impairments {
+            loss: 0.0,
+            duplicate: 0.0,
+            reorder: 0.0,
+            reorder_delay: Duration::from_millis(20),
+            truncate: 0.0,
+            delay: Duration::ZERO,
+            jitter: Duration::ZERO,
+            seed: 0,
+        }
+    }
+}
+
+/// What the proxy did to the packets it saw.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub received: u64,
+    pub sent: u64,
+    pub dropped: u64,
+    pub duplicated: u64,
+    pub reordered: u64,
+    pub truncated: u64,
+}
+
+#[derive(Default)]
+struct Counters {
+    received: AtomicU64,
+    sent: AtomicU64,
+    dropped: AtomicU64,
+    duplicated: AtomicU64,
+    reordered: AtomicU64,
+    truncated: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Route {
+    ToServer(SocketAddr),
+    ToClient(SocketAddr),
+}
+
+/// How long socket readers block before checking whether the proxy stopped.
+const READ_TIMEOUT: Duration = Duration::from_millis(100);
+
+enum Event {
+    Packet(Route, Vec<u8>),
+    Stop,
+}
+
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+struct Scheduled {
+    at: Instant,
+    seq: u64,
+    route: Route,
+    data: Vec<u8>,
+}
+
+/// The socket a client's packets reach the server through, and the random
+/// decisions for each direction of its traffic.
+struct Upstream {
+    socket: UdpSocket,
+    to_server: StdRng,
+    to_client: StdRng,
+}
+
+struct Proxy {
+    front: UdpSocket,
+    server: SocketAddr,
+    upstreams: HashMap<SocketAddr, Upstream>,
+    queue: BinaryHeap<Reverse<Scheduled>>,
+    seq: u64,
+    impairments: Impairments,
+    counters: Arc<Counters>,
+    /// Where the readers of new upstream sockets send their packets.
+    events: Sender<Event>,
+    stop: Arc<AtomicBool>,
+}
+
+/// splitmix64, which spreads nearby inputs over the whole range and gives the
+/// same result on every platform and Rust version.
+fn splitmix64(x: u64) -> u64 {
+    let mut z = x.wrapping_add(0x9e3779b97f4a7c15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+    z ^ (z >> 31)
+}
+
+/// Passes every packet arriving on `socket` to the proxy thread until the
+/// proxy stops.
+fn read_packets(
+    socket: UdpSocket,
+    route: impl Fn(SocketAddr) -> Route,
+    events: Sender<Event>,
+    stop: Arc<AtomicBool>,
+) {
+    let mut buf = [0; 65536];
+
+    while !stop.load(Ordering::Relaxed) {
+        // Errors are mostly the read timeout, and otherwise ICMP errors for
+        // earlier packets, which a lossy network may as well swallow.
+        if let Ok((size, src)) = socket.recv_from(&mut buf) {
+            let packet = Event::Packet(route(src), buf[..size].to_vec());
+            if events.send(packet).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+impl Proxy {
+    fn run(mut self, events: Receiver<Event>) {
+        loop {
+            // Sleep until a packet arrives or the next one is due.
+            let event = match self.queue.peek() {
+                Some(Reverse(next)) => {
+                    events.recv_timeout(next.at.saturating_duration_since(Instant::now()))
+                }
+                None => events.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            };
+
+            match event {
+                Ok(Event::Packet(route, data)) => {
+                    let known = match route {
+                        Route::ToServer(client) => self.upstream(client).is_ok(),
+                        Route::ToClient(_) => true,
+                    };
+                    if known {
+                        self.schedule(route, &data);
+                    }
+                }
+                Ok(Event::Stop) | Err(RecvTimeoutError::Disconnected) => return,
+                Err(RecvTimeoutError::Timeout) => (),
+            }
+
+            self.send_due();
+        }
+    }
+
+    fn upstream(&mut self, client: SocketAddr) -> io::Result<&Upstream> {
+        if !self.upstreams.contains_key(&client) {
+            let bind: SocketAddr = if self.server.is_ipv4() {
+                "0.0.0.0:0".parse().unwrap()
+            } else {
+                "[::]:0".parse().unwrap()
+            };
+            let socket = UdpSocket::bind(bind)?;
+            socket.connect(self.server)?;
+            socket.set_read_timeout(Some(READ_TIMEOUT))?;
+
+            let reader = socket.try_clone()?;
+            let events = self.events.clone();
+            let stop = self.stop.clone();
+            thread::spawn(move || read_packets(reader, |_| Route::ToClient(client), events, stop));
+
+            // Seeded by the order clients showed up in, not their ephemeral
+            // ports, so reruns of the same traffic repeat the same faults.
+            let index = self.upstreams.len() as u64;
+            let seed = splitmix64(self.impairments.seed);
+            let rng = |direction: u64| {
+                let seed = splitmix64(splitmix64(seed ^ index) ^ direction);
+                StdRng::seed_from_u64(seed)
+            };
+            let upstream = Upstream {
+                socket,
+                to_server: rng(0),
+                to_client: rng(1),
+            };
+            self.upstreams.insert(client, upstream);
+        }
+
+        Ok(&self.upstreams[&client])
+    }
+
+    fn schedule(&mut self, route: Route, data: &[u8]) {
+        let imp = &self.impairments;
+        let rng = match route {
+            Route::ToServer(client) => &mut self.upstreams.get_mut(&client).unwrap().to_server,
+            Route::ToClient(client) => &mut self.upstreams.get_mut(&client).unwrap().to_client,
+        };
+        self.counters.received.fetch_add(1, Ordering::Relaxed);
+
+        if rng.random_bool(imp.loss) {
+            self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+
+        let copies = if rng.random_bool(imp.duplicate) {
+            self.counters.duplicated.fetch_add(1, Ordering::Relaxed);
+            2
+        } else {
+            1
+        };
+
+        for _ in 0..copies {
+            let mut data = data.to_vec();
+            if !data.is_empty() && rng.random_bool(imp.truncate) {
+                self.counters.truncated.fetch_add(1, Ordering::Relaxed);
+                data.truncate(rng.random_range(0..data.len()));
+            }
+
+            let mut delay = imp.delay;
+            if !imp.jitter.is_zero() {
+                delay += imp.jitter.mul_f64(rng.random::<f64>());
+            }
+            if rng.random_bool(imp.reorder) {
+                self.counters.reordered.fetch_add(1, Ordering::Relaxed);
+                delay += imp.reorder_delay;
+            }
+
+            self.seq += 1;
+            self.queue.push(Reverse(Scheduled {
+                at: Instant::now() + delay,
+                seq: self.seq,
+                route,
+                data,
+            }));
+        }
+    }
+
+    /// Sends every packet whose delay has passed.
+    fn send_due(&mut self) {
+        let now = Instant::now();
+
+        while self.queue.peek().is_some_and(|Reverse(s)| s.at <= now) {
+            let Reverse(scheduled) = self.queue.pop().unwrap();
+            let res = match scheduled.route {
+                Route::ToServer(client) => self.upstreams[&client].socket.send(&scheduled.data),
+                Route::ToClient(client) => self.front.send_to(&scheduled.data, client),
+            };
+
+            if res.is_ok() {
+                self.counters.sent.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+}
+
+/// A running impairment proxy. It stops when dropped.
+pub struct LossyProxy {
+    local_addr: SocketAddr,
+    counters: Arc<Counters>,
+    events: Sender<Event>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl LossyProxy {
+    /// Starts a proxy on an ephemeral localhost port that forwards to `server`.
+    pub fn start(server: SocketAddr, impairments: Impairments) -> io::Result<LossyProxy> {
+        let front = UdpSocket::bind((server.ip(), 0))?;
+        front.set_read_timeout(Some(READ_TIMEOUT))?;
+        let local_addr = front.local_addr()?;
+
+        let counters = Arc::new(Counters::default());
+        let stop = Arc::new(AtomicBool::new(false));
+        let (events, received) = mpsc::channel();
+
+        let reader = front.try_clone()?;
+        let reader_events = events.clone();
+        let reader_stop = stop.clone();
+        thread::spawn(move || read_packets(reader, Route::ToServer, reader_events, reader_stop));
+
+        let proxy = Proxy {
+            front,
+            server,
+            upstreams: HashMap::new(),
+            queue: BinaryHeap::new(),
+            seq: 0,
+            impairments,
+            counters: counters.clone(),
+            events: events.clone(),
+            stop: stop.clone(),
+        };
+        let thread = thread::spawn(move || proxy.run(received));
+
+        Ok(LossyProxy {
+            local_addr,
+            counters,
+            events,
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    pub fn stats(&self) -> Stats {
+        let c = &self.counters;
+        Stats {
+            received: c.received.load(Ordering::Relaxed),
+            sent: c.sent.load(Ordering::Relaxed),
+            dropped: c.dropped.load(Ordering::Relaxed),
+            duplicated: c.duplicated.load(Ordering::Relaxed),
+            reordered: c.reordered.load(Ordering::Relaxed),
+            truncated: c.truncated.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Drop for LossyProxy {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        let _ = self.events.send(Event::Stop);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Echo server on its own thread, returns its address.
+    fn echo_server() -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut buf = [0; 1000];
+            while let Ok((size, src)) = socket.recv_from(&mut buf) {
+                let _ = socket.send_to(&buf[..size], src);
+            }
+        });
+        addr
+    }
+
+    fn client(proxy: &LossyProxy) -> UdpSocket {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.connect(proxy.local_addr()).unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+        socket
+    }
+
+    fn recv_all(socket: &UdpSocket) -> Vec<Vec<u8>> {
+        let mut packets = Vec::new();
+        let mut buf = [0; 1000];
+        while let Ok(size) = socket.recv(&mut buf) {
+            packets.push(buf[..size].to_vec());
+        }
+        packets
+    }
+
+    #[test]
+    fn clean_network_forwards_everything() {
+        let proxy = LossyProxy::start(echo_server(), Impairments::default()).unwrap();
+        let socket = client(&proxy);
+
+        for i in 0..10u8 {
+            socket.send(&[i]).unwrap();
+        }
+
+        let packets = recv_all(&socket);
+        assert_eq!(packets, (0..10u8).map(|i| vec![i]).collect::<Vec<_>>());
+        assert_eq!(proxy.stats().sent, 20);
+    }
+
+    #[test]
+    fn loss_and_duplication() {
+        let lossy = Impairments {
+            loss: 1.0,
+            ..Default::default()
+        };
+        let proxy = LossyProxy::start(echo_server(), lossy).unwrap();
+        let socket = client(&proxy);
+        socket.send(b"hello").unwrap();
+        assert!(recv_all(&socket).is_empty());
+        assert_eq!(proxy.stats().dropped, 1);
+
+        let doubled = Impairments {
+            duplicate: 1.0,
+            ..Default::default()
+        };
+        let proxy = LossyProxy::start(echo_server(), doubled).unwrap();
+        let socket = client(&proxy);
+        socket.send(b"hello").unwrap();
+        // Duplicated on the way there and again on the way back.
+        assert_eq!(recv_all(&socket).len(), 4);
+    }
+
+    #[test]
+    fn truncation() {
+        let truncating = Impairments {
+            truncate: 1.0,
+            ..Default::default()
+        };
+        let proxy = LossyProxy::start(echo_server(), truncating).unwrap();
+        let socket = client(&proxy);
+        socket.send(b"hello world").unwrap();
+
+        for packet in recv_all(&socket) {
+            assert!(b"hello world".starts_with(&packet) && packet.len() < 11);
+        }
+    }
+
+    #[test]
+    fn reordering() {
+        let reordering = Impairments {
+            reorder: 0.5,
+            reorder_delay: Duration::from_millis(50),
+            seed: 3,
+            ..Default::default()
+        };
+        let proxy = LossyProxy::start(echo_server(), reordering).unwrap();
+        let socket = client(&proxy);
+
+        for i in 0..20u8 {
+            socket.send(&[i]).unwrap();
+        }
+
+        let mut packets = recv_all(&socket);
+        assert_eq!(packets.len(), 20);
+        assert!(proxy.stats().reordered > 0);
+        assert!(!packets.is_sorted());
+        packets.sort();
+        assert_eq!(packets, (0..20u8).map(|i| vec![i]).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn same_seed_same_faults() {
+        let run = |seed| {
+            let impairments = Impairments {
+                loss: 0.3,
+                duplicate: 0.3,
+                truncate: 0.3,
+                seed,
+                ..Default::default()
+            };
+            let proxy = LossyProxy::start(echo_server(), impairments).unwrap();
+            let socket = client(&proxy);
+            for i in 0..50u8 {
+                socket.send(&[i; 8]).unwrap();
+            }
+            (recv_all(&socket), proxy.stats())
+        };
+
+        let (packets, stats) = run(7);
+        assert!(stats.dropped > 0 && stats.duplicated > 0 && stats.truncated > 0);
+        assert_eq!(run(7), (packets, stats));
+    }
+}
@@ -1,6 +1,7 @@
+use prime_time_1::is_prime;
 use serde::{Deserialize, Serialize};
 use std::{
-    io::{BufRead, BufReader, Read, Write},
+    io::{BufRead, BufReader, Write},
     net::{TcpListener, TcpStream},
     thread,
 };
@@ -41,23 +42,19 @@ fn handle_client(mut stream: TcpStream) {
             malformed = true;
         }
 
-        let response: Response;
-        if malformed {
+        let response = if malformed {
             println!("Malformed!");
-            response = Response {
+            Response {
                 method: String::from("malformed"),
                 prime: false,
-            };
+            }
         } else {
             println!("Valid!");
-            response = Response {
+            Response {
                 method: String::from("isPrime"),
-                prime: match req.number as u64 {
-                    ..=1 => false,
-                    2.. => primes::is_prime(req.number as u64),
-                },
+                prime: is_prime(req.number as u64),
             }
-        }
+        };
 
         println!("{:?}", response);
         let mut response = serde_json::to_string(&response).unwrap();
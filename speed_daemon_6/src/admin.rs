@@ -0,0 +1,229 @@
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+
+use crate::client::Role;
+use crate::ledger::{ticket_days, Ledger};
+use crate::observations::ObservationStore;
+
+/// A request on the admin listener, one JSON object per line.
+///
+/// `{"command": "connections"}` lists cameras and dispatchers per road,
+/// `{"command": "pending"}` the queued tickets, `{"command": "plates"}` the
+/// sightings per plate, `{"command": "days"}` tickets issued per day, counted
+/// on the day each ticket starts, and `{"command": "plate", "plate": "UN1X"}`
+/// everything known about one plate.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum Request {
+    Connections,
+    Pending,
+    Plates,
+    Days,
+    Plate { plate: String },
+}
+
+/// Read-only view of the daemon state that admin requests are answered from.
+pub struct Snapshot<'a> {
+    /// Identified connections, keyed by connection id.
+    pub roles: &'a HashMap<u64, Role>,
+    pub observations: &'a ObservationStore,
+    pub ledger: &'a Ledger,
+}
+
+impl Snapshot<'_> {
+    /// Answers a single request line. Malformed requests get an `error` field.
+    pub fn answer(&self, line: &str) -> Value {
+        match serde_json::from_str(line) {
+            Ok(request) => self.query(request),
+            Err(e) => json!({ "error": e.to_string() }),
+        }
+    }
+
+    pub fn query(&self, request: Request) -> Value {
+        match request {
+            Request::Connections => self.connections(),
+            Request::Pending => self.pending(),
+            Request::Plates => self.plates(),
+            Request::Days => self.days(),
+            Request::Plate { plate } => self.plate(&plate),
+        }
+    }
+
+    fn connections(&self) -> Value {
+        let mut roads: BTreeMap<u16, (Vec<Value>, Vec<u64>)> = BTreeMap::new();
+
+        for (id, role) in self.roles {
+            match role {
+                Role::Camera { road, mile, limit } => {
+                    roads.entry(*road).or_default().0.push(json!({
+                        "id": id,
+                        "mile": mile,
+                        "limit": limit,
+                    }));
+                }
+                Role::Dispatcher { roads: covered } => {
+                    for road in covered {
+                        roads.entry(*road).or_default().1.push(*id);
+                    }
+                }
+            }
+        }
+
+        let roads: Vec<Value> = roads
+            .into_iter()
+            .map(|(road, (mut cameras, mut dispatchers))| {
+                cameras.sort_by_key(|c| c["id"].as_u64());
+                dispatchers.sort();
+                json!({ "road": road, "cameras": cameras, "dispatchers": dispatchers })
+            })
+            .collect();
+
+        json!({ "roads": roads })
+    }
+
+    fn pending(&self) -> Value {
+        let mut roads: BTreeMap<u16, Vec<Value>> = BTreeMap::new();
+        for ticket in self.ledger.pending() {
+            roads
+                .entry(ticket.road)
+                .or_default()
+                .push(serde_json::to_value(ticket).unwrap_or_default());
+        }
+
+        let roads: Vec<Value> = roads
+            .into_iter()
+            .map(|(road, tickets)| json!({ "road": road, "tickets": tickets }))
+            .collect();
+
+        json!({ "roads": roads })
+    }
+
+    fn plates(&self) -> Value {
+        let mut plates: BTreeMap<String, usize> = BTreeMap::new();
+        for sighting in self.observations.sightings() {
+            *plates.entry(sighting.plate).or_default() += 1;
+        }
+
+        json!({ "plates": plates })
+    }
+
+    fn days(&self) -> Value {
+        json!({ "days": self.ledger.issued_per_day() })
+    }
+
+    fn plate(&self, plate: &str) -> Value {
+        let mut sightings: Vec<Value> = self
+            .observations
+            .sightings()
+            .filter(|s| s.plate == plate)
+            .map(|s| {
+                json!({
+                    "road": s.road,
+                    "limit": s.limit,
+                    "mile": s.mile,
+                    "timestamp": s.timestamp,
+                })
+            })
+            .collect();
+        sightings.sort_by_key(|s| (s["road"].as_u64(), s["timestamp"].as_u64()));
+
+        let mut days: Vec<u32> = self
+            .ledger
+            .ticketed_days()
+            .filter(|(p, _)| p.as_str() == plate)
+            .flat_map(|(_, days)| days.iter().copied())
+            .collect();
+        days.sort();
+
+        let pending: Vec<Value> = self
+            .ledger
+            .pending()
+            .filter(|t| t.plate == plate)
+            .map(|t| json!({ "ticket": t, "days": ticket_days(t).collect::<Vec<u32>>() }))
+            .collect();
+
+        json!({
+            "plate": plate,
+            "sightings": sightings,
+            "ticketed_days": days,
+            "pending": pending,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn answers_requests() {
+        let mut roles = HashMap::new();
+        roles.insert(
+            0,
+            Role::Camera {
+                road: 1,
+                mile: 8,
+                limit: 60,
+            },
+        );
+        roles.insert(
+            1,
+            Role::Camera {
+                road: 1,
+                mile: 9,
+                limit: 60,
+            },
+        );
+
+        let mut observations = ObservationStore::new();
+        let mut ledger = Ledger::new();
+        observations.record(1, 60, 8, "UN1X", 0);
+        for ticket in observations.record(1, 60, 9, "UN1X", 45) {
+            ledger.issue(ticket);
+        }
+        observations.record(1, 60, 8, "RE05BKG", 10);
+
+        // Spans days 1 and 2 but is a single ticket.
+        observations.record(1, 60, 0, "L0NG", 90000);
+        for ticket in observations.record(1, 60, 2000, "L0NG", 180000) {
+            ledger.issue(ticket);
+        }
+
+        let snapshot = Snapshot {
+            roles: &roles,
+            observations: &observations,
+            ledger: &ledger,
+        };
+
+        assert_eq!(
+            snapshot.answer(r#"{"command": "connections"}"#),
+            json!({ "roads": [{
+                "road": 1,
+                "cameras": [{"id": 0, "mile": 8, "limit": 60}, {"id": 1, "mile": 9, "limit": 60}],
+                "dispatchers": [],
+            }]})
+        );
+        assert_eq!(
+            snapshot.answer(r#"{"command": "plates"}"#),
+            json!({ "plates": { "L0NG": 2, "RE05BKG": 1, "UN1X": 2 } })
+        );
+        assert_eq!(
+            snapshot.answer(r#"{"command": "days"}"#),
+            json!({ "days": { "0": 1, "1": 1 } })
+        );
+        assert_eq!(
+            snapshot.answer(r#"{"command": "pending"}"#)["roads"][0]["tickets"][0]["speed"],
+            json!(8000)
+        );
+        assert_eq!(
+            snapshot.answer(r#"{"command": "plate", "plate": "UN1X"}"#)["ticketed_days"],
+            json!([0])
+        );
+        assert!(snapshot.answer("{").get("error").is_some());
+        assert!(snapshot
+            .answer(r#"{"command": "reboot"}"#)
+            .get("error")
+            .is_some());
+    }
+}
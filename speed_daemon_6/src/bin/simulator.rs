@@ -0,0 +1,434 @@
+//! Drives a running speed daemon with simulated cameras and dispatchers, then
+//! checks the tickets it hands out against an offline calculation.
+//!
+//! Run it against a daemon with an empty journal, tickets issued in earlier
+//! runs count against the same plates and days. Needs the `simulator`
+//! feature: `cargo run --features simulator --bin simulator`.
+
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use speed_daemon_6::message::{self, Message};
+use std::collections::{HashMap, HashSet};
+use std::process::ExitCode;
+use std::time::{Duration, Instant};
+use tokio::io::AsyncWriteExt;
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::{self, UnboundedSender};
+
+const USAGE: &str = "Usage: simulator [--addr HOST:PORT] [--limits 60,50,...] [--cameras N] \
+[--plates N] [--trips N] [--days N] [--speeding P] [--dispatchers N] \
+[--dispatcher-delay-ms MS] [--settle-ms MS] [--seed N]";
+
+struct Config {
+    addr: String,
+    /// Speed limit of each road, road numbers are the indexes.
+    limits: Vec<u16>,
+    cameras: usize,
+    plates: usize,
+    trips: usize,
+    days: u32,
+    speeding: f64,
+    dispatchers: usize,
+    dispatcher_delay: Duration,
+    settle: Duration,
+    seed: u64,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            addr: String::from("127.0.0.1:8080"),
+            limits: vec![60, 50, 70],
+            cameras: 4,
+            plates: 100,
+            trips: 3,
+            days: 3,
+            speeding: 0.2,
+            dispatchers: 2,
+            dispatcher_delay: Duration::from_millis(0),
+            settle: Duration::from_millis(1000),
+            seed: 1,
+        }
+    }
+}
+
+fn parse<T: std::str::FromStr>(flag: &str, value: Option<String>) -> Result<T, String> {
+    value
+        .ok_or(format!("Missing value for {flag}"))?
+        .parse()
+        .map_err(|_| format!("Invalid value for {flag}"))
+}
+
+impl Config {
+    fn from_args(mut args: impl Iterator<Item = String>) -> Result<Config, String> {
+        let mut config = Config::default();
+
+        while let Some(flag) = args.next() {
+            let value = args.next();
+            match flag.as_str() {
+                "--addr" => config.addr = parse(&flag, value)?,
+                "--limits" => {
+                    config.limits = parse::<String>(&flag, value)?
+                        .split(',')
+                        .map(|limit| parse(&flag, Some(String::from(limit))))
+                        .collect::<Result<Vec<u16>, String>>()?
+                }
+                "--cameras" => config.cameras = parse(&flag, value)?,
+                "--plates" => config.plates = parse(&flag, value)?,
+                "--trips" => config.trips = parse(&flag, value)?,
+                "--days" => config.days = parse(&flag, value)?,
+                "--speeding" => config.speeding = parse(&flag, value)?,
+                "--dispatchers" => config.dispatchers = parse(&flag, value)?,
+                "--dispatcher-delay-ms" => {
+                    config.dispatcher_delay = Duration::from_millis(parse(&flag, value)?)
+                }
+                "--settle-ms" => config.settle = Duration::from_millis(parse(&flag, value)?),
+                "--seed" => config.seed = parse(&flag, value)?,
+                _ => return Err(format!("Unknown flag {flag}")),
+            }
+        }
+
+        if config.limits.is_empty() || config.cameras < 2 || config.days == 0 {
+            return Err(String::from(
+                "Need at least one road, two cameras and one day",
+            ));
+        }
+        if !(0.0..=1.0).contains(&config.speeding) {
+            return Err(String::from("--speeding must be between 0 and 1"));
+        }
+
+        Ok(config)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct Sighting {
+    road: u16,
+    mile: u16,
+    plate: String,
+    timestamp: u32,
+}
+
+struct Camera {
+    road: u16,
+    mile: u16,
+    limit: u16,
+    plates: Vec<(String, u32)>,
+}
+
+/// Lays out cameras on every road and drives each plate past them.
+fn generate(config: &Config, rng: &mut StdRng) -> (Vec<Camera>, Vec<Sighting>) {
+    let mut cameras = Vec::new();
+    let mut sightings = Vec::new();
+
+    for (road, limit) in config.limits.iter().enumerate() {
+        let mut miles = HashSet::new();
+        while miles.len() < config.cameras {
+            miles.insert(rng.random_range(0..1000u16));
+        }
+        let mut miles: Vec<u16> = miles.into_iter().collect();
+        miles.sort();
+
+        for mile in miles {
+            cameras.push(Camera {
+                road: road as u16,
+                mile,
+                limit: *limit,
+                plates: Vec::new(),
+            });
+        }
+    }
+
+    for plate in 0..config.plates {
+        let plate = format!("SIM{plate:05}");
+
+        for _ in 0..config.trips {
+            let road = rng.random_range(0..config.limits.len()) as u16;
+            let limit = config.limits[road as usize] as f64;
+            let speed = if rng.random_bool(config.speeding) {
+                limit + rng.random_range(1.0..30.0)
+            } else {
+                rng.random_range((limit / 2.0)..limit)
+            };
+            let start = rng.random_range(0..config.days * 86400);
+
+            let on_road = cameras.iter_mut().filter(|c| c.road == road);
+            let mut first_mile = None;
+            for camera in on_road {
+                let first_mile = *first_mile.get_or_insert(camera.mile);
+                let hours = (camera.mile - first_mile) as f64 / speed;
+                let timestamp = start + (hours * 3600.0).round() as u32;
+
+                camera.plates.push((plate.clone(), timestamp));
+                sightings.push(Sighting {
+                    road,
+                    mile: camera.mile,
+                    plate: plate.clone(),
+                    timestamp,
+                });
+            }
+        }
+    }
+
+    (cameras, sightings)
+}
+
+async fn run_camera(addr: &str, camera: Camera) -> Result<(), String> {
+    let mut stream = TcpStream::connect(addr).await.map_err(|e| e.to_string())?;
+
+    let mut bytes = Message::IAmCamera {
+        road: camera.road,
+        mile: camera.mile,
+        limit: camera.limit,
+    }
+    .encode()
+    .map_err(|e| e.to_string())?;
+    for (plate, timestamp) in camera.plates {
+        bytes.extend(
+            Message::Plate { plate, timestamp }
+                .encode()
+                .map_err(|e| e.to_string())?,
+        );
+    }
+
+    stream.write_all(&bytes).await.map_err(|e| e.to_string())?;
+    stream.flush().await.map_err(|e| e.to_string())
+}
+
+async fn run_dispatcher(
+    addr: String,
+    road: u16,
+    tickets: UnboundedSender<Message>,
+) -> Result<(), String> {
+    let mut stream = TcpStream::connect(addr).await.map_err(|e| e.to_string())?;
+    let hello = Message::IAmDispatcher { roads: vec![road] }
+        .encode()
+        .map_err(|e| e.to_string())?;
+    stream.write_all(&hello).await.map_err(|e| e.to_string())?;
+
+    let mut buf = Vec::new();
+    loop {
+        match message::read_message(&mut stream, &mut buf).await {
+            Ok(Some(msg @ Message::Ticket { .. })) => {
+                let _ = tickets.send(msg);
+            }
+            Ok(Some(other)) => return Err(format!("Unexpected message {:?}", other)),
+            Ok(None) => return Ok(()),
+            Err(e) => return Err(e.to_string()),
+        }
+    }
+}
+
+/// Checks received tickets against the sightings that were sent.
+///
+/// Every ticket must be backed by two real sightings that prove speeding and
+/// no plate may get two tickets on the same day. Every speeding pair must
+/// share a day with a ticket for its plate, otherwise the daemon missed it.
+fn verify(config: &Config, sightings: &[Sighting], tickets: &[Message]) -> Vec<String> {
+    let mut problems = Vec::new();
+    let known: HashSet<&Sighting> = sightings.iter().collect();
+    let mut ticketed_days: HashMap<&str, HashSet<u32>> = HashMap::new();
+
+    for ticket in tickets {
+        let Message::Ticket {
+            plate,
+            road,
+            mile1,
+            timestamp1,
+            mile2,
+            timestamp2,
+            speed,
+        } = ticket
+        else {
+            continue;
+        };
+
+        for (mile, timestamp) in [(*mile1, *timestamp1), (*mile2, *timestamp2)] {
+            let sighting = Sighting {
+                road: *road,
+                mile,
+                plate: plate.clone(),
+                timestamp,
+            };
+            if !known.contains(&sighting) {
+                problems.push(format!("Ticket for unknown sighting {:?}", sighting));
+            }
+        }
+
+        let limit = config.limits[*road as usize] as f64;
+        let expected = mile1.abs_diff(*mile2) as f64 * 3600.0
+            / timestamp2.saturating_sub(*timestamp1).max(1) as f64;
+        // Speeds above 655.35 mph do not fit in the ticket and are capped.
+        let encoded = (expected * 100.0).min(u16::MAX as f64);
+        if expected < limit + 0.5 || (encoded - *speed as f64).abs() > 1.0 {
+            problems.push(format!(
+                "Wrong ticket {:?}, expected {:.2} mph",
+                ticket, expected
+            ));
+        }
+
+        let days = ticketed_days.entry(plate).or_default();
+        for day in timestamp1 / 86400..=timestamp2 / 86400 {
+            if !days.insert(day) {
+                problems.push(format!("Second ticket for {plate} on day {day}"));
+            }
+        }
+    }
+
+    let mut by_plate: HashMap<(u16, &str), Vec<&Sighting>> = HashMap::new();
+    for sighting in sightings {
+        by_plate
+            .entry((sighting.road, &sighting.plate))
+            .or_default()
+            .push(sighting);
+    }
+
+    for ((road, plate), sightings) in by_plate {
+        let limit = config.limits[road as usize] as f64;
+        for (i, a) in sightings.iter().enumerate() {
+            for b in &sightings[i + 1..] {
+                let (a, b) = if a.timestamp <= b.timestamp {
+                    (a, b)
+                } else {
+                    (b, a)
+                };
+                if a.timestamp == b.timestamp {
+                    continue;
+                }
+
+                let speed =
+                    a.mile.abs_diff(b.mile) as f64 * 3600.0 / (b.timestamp - a.timestamp) as f64;
+                let covered = ticketed_days.get(plate).is_some_and(|days| {
+                    (a.timestamp / 86400..=b.timestamp / 86400).any(|day| days.contains(&day))
+                });
+
+                if speed >= limit + 0.5 && !covered {
+                    problems.push(format!(
+                        "Missed ticket for {plate} on road {road}: {:.2} mph between {} and {}",
+                        speed, a.timestamp, b.timestamp
+                    ));
+                }
+            }
+        }
+    }
+
+    problems
+}
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    let config = match Config::from_args(std::env::args().skip(1)) {
+        Ok(config) => config,
+        Err(e) => {
+            println!("{e}\n{USAGE}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let mut rng = StdRng::seed_from_u64(config.seed);
+    let (cameras, sightings) = generate(&config, &mut rng);
+    println!(
+        "Simulating {} cameras, {} sightings on {} roads",
+        cameras.len(),
+        sightings.len(),
+        config.limits.len()
+    );
+
+    let (sender, mut receiver) = mpsc::unbounded_channel();
+    let dispatch_addr = config.addr.clone();
+    let dispatch_delay = config.dispatcher_delay;
+    let dispatchers = config.dispatchers;
+    let roads = config.limits.len() as u16;
+    tokio::spawn(async move {
+        tokio::time::sleep(dispatch_delay).await;
+        for road in 0..roads {
+            for _ in 0..dispatchers {
+                let addr = dispatch_addr.clone();
+                let sender = sender.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = run_dispatcher(addr, road, sender).await {
+                        println!("Dispatcher failed: {e}");
+                    }
+                });
+            }
+        }
+    });
+
+    let start = Instant::now();
+    let tasks: Vec<_> = cameras
+        .into_iter()
+        .map(|camera| {
+            let addr = config.addr.clone();
+            tokio::spawn(async move { run_camera(&addr, camera).await })
+        })
+        .collect();
+    for task in tasks {
+        if let Ok(Err(e)) = task.await {
+            println!("Camera failed: {e}");
+            return ExitCode::FAILURE;
+        }
+    }
+    let sent = start.elapsed();
+
+    // Collect tickets until the daemon has been quiet for a while.
+    let mut tickets = Vec::new();
+    while let Ok(Some(ticket)) = tokio::time::timeout(
+        config.settle + config.dispatcher_delay.saturating_sub(start.elapsed()),
+        receiver.recv(),
+    )
+    .await
+    {
+        tickets.push(ticket);
+    }
+
+    println!(
+        "Sent {} sightings in {:.2?} ({:.0}/s), received {} tickets",
+        sightings.len(),
+        sent,
+        sightings.len() as f64 / sent.as_secs_f64(),
+        tickets.len()
+    );
+
+    let problems = verify(&config, &sightings, &tickets);
+    if problems.is_empty() {
+        println!("All tickets match");
+        ExitCode::SUCCESS
+    } else {
+        problems.iter().for_each(|p| println!("{p}"));
+        println!("{} problems", problems.len());
+        ExitCode::FAILURE
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use speed_daemon_6::ledger::Ledger;
+    use speed_daemon_6::observations::ObservationStore;
+
+    #[test]
+    fn seeded_run_triggers_expected_tickets() {
+        let config = Config {
+            plates: 30,
+            seed: 7,
+            ..Config::default()
+        };
+        let (_, sightings) = generate(&config, &mut StdRng::seed_from_u64(config.seed));
+        let (_, again) = generate(&config, &mut StdRng::seed_from_u64(config.seed));
+        assert_eq!(sightings, again);
+
+        // The daemon's own bookkeeping tickets exactly the speeding trips.
+        let mut observations = ObservationStore::new();
+        let mut ledger = Ledger::new();
+        for s in &sightings {
+            let limit = config.limits[s.road as usize];
+            for ticket in observations.record(s.road, limit, s.mile, &s.plate, s.timestamp) {
+                ledger.issue(ticket);
+            }
+        }
+        let tickets: Vec<Message> = ledger.pending().cloned().map(Message::from).collect();
+
+        assert!(!tickets.is_empty());
+        assert_eq!(verify(&config, &sightings, &tickets), Vec::<String>::new());
+    }
+}
@@ -0,0 +1,178 @@
+use crate::message::Message;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Role {
+    Camera { road: u16, mile: u16, limit: u16 },
+    Dispatcher { roads: Vec<u16> },
+}
+
+/// What the server should do in response to a message from a client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    Identified(Role),
+    Observation {
+        road: u16,
+        mile: u16,
+        limit: u16,
+        plate: String,
+        timestamp: u32,
+    },
+    WantHeartbeat {
+        interval: u32,
+    },
+}
+
+/// Per-connection protocol state.
+///
+/// A connection starts unidentified and is locked into a role by the first
+/// IAmCamera or IAmDispatcher message. Heartbeats may be requested once, in
+/// any role. Anything else out of place is an error, after which the server
+/// sends an Error message and disconnects.
+#[derive(Debug, Default)]
+pub struct Client {
+    role: Option<Role>,
+    wants_heartbeat: bool,
+}
+
+impl Client {
+    pub fn new() -> Client {
+        Client::default()
+    }
+
+    pub fn role(&self) -> Option<&Role> {
+        self.role.as_ref()
+    }
+
+    pub fn handle(&mut self, msg: Message) -> Result<Action, String> {
+        match msg {
+            Message::IAmCamera { road, mile, limit } => {
+                self.identify(Role::Camera { road, mile, limit })
+            }
+            Message::IAmDispatcher { roads } => self.identify(Role::Dispatcher { roads }),
+            Message::Plate { plate, timestamp } => match self.role {
+                Some(Role::Camera { road, mile, limit }) => Ok(Action::Observation {
+                    road,
+                    mile,
+                    limit,
+                    plate,
+                    timestamp,
+                }),
+                _ => Err(String::from("Plate from non-camera client")),
+            },
+            Message::WantHeartbeat { interval } => {
+                if self.wants_heartbeat {
+                    return Err(String::from("Heartbeat already requested"));
+                }
+
+                self.wants_heartbeat = true;
+                Ok(Action::WantHeartbeat { interval })
+            }
+            _ => Err(String::from("Illegal message from client")),
+        }
+    }
+
+    fn identify(&mut self, role: Role) -> Result<Action, String> {
+        if self.role.is_some() {
+            return Err(String::from("Client already identified"));
+        }
+
+        self.role = Some(role.clone());
+        Ok(Action::Identified(role))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn camera_sends_plates() {
+        let mut client = Client::new();
+        let camera = Role::Camera {
+            road: 123,
+            mile: 8,
+            limit: 60,
+        };
+
+        assert_eq!(
+            client.handle(Message::IAmCamera {
+                road: 123,
+                mile: 8,
+                limit: 60
+            }),
+            Ok(Action::Identified(camera.clone()))
+        );
+        assert_eq!(client.role(), Some(&camera));
+        assert_eq!(
+            client.handle(Message::Plate {
+                plate: String::from("UN1X"),
+                timestamp: 0
+            }),
+            Ok(Action::Observation {
+                road: 123,
+                mile: 8,
+                limit: 60,
+                plate: String::from("UN1X"),
+                timestamp: 0
+            })
+        );
+    }
+
+    #[test]
+    fn role_is_locked() {
+        let mut client = Client::new();
+        client
+            .handle(Message::IAmDispatcher { roads: vec![1] })
+            .unwrap();
+
+        assert!(client
+            .handle(Message::IAmCamera {
+                road: 1,
+                mile: 1,
+                limit: 1
+            })
+            .is_err());
+        assert!(client
+            .handle(Message::IAmDispatcher { roads: vec![2] })
+            .is_err());
+    }
+
+    #[test]
+    fn plate_from_non_camera() {
+        let plate = Message::Plate {
+            plate: String::from("UN1X"),
+            timestamp: 0,
+        };
+
+        assert!(Client::new().handle(plate.clone()).is_err());
+
+        let mut dispatcher = Client::new();
+        dispatcher
+            .handle(Message::IAmDispatcher { roads: vec![1] })
+            .unwrap();
+        assert!(dispatcher.handle(plate).is_err());
+    }
+
+    #[test]
+    fn heartbeat_requested_once() {
+        let mut client = Client::new();
+
+        assert_eq!(
+            client.handle(Message::WantHeartbeat { interval: 0 }),
+            Ok(Action::WantHeartbeat { interval: 0 })
+        );
+        assert!(client
+            .handle(Message::WantHeartbeat { interval: 10 })
+            .is_err());
+    }
+
+    #[test]
+    fn server_messages_are_illegal() {
+        assert!(Client::new().handle(Message::Heartbeat).is_err());
+        assert!(Client::new()
+            .handle(Message::Error {
+                msg: String::from("hi")
+            })
+            .is_err());
+    }
+}
@@ -0,0 +1,315 @@
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+
+use crate::ledger::{ticket_days, Ledger};
+use crate::observations::{ObservationStore, Ticket};
+
+/// One line of the journal.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Entry {
+    Observation {
+        road: u16,
+        limit: u16,
+        mile: u16,
+        plate: String,
+        timestamp: u32,
+    },
+    /// A ticket passed the one-per-day check and is owed to a dispatcher.
+    Ticket { ticket: Ticket },
+    /// A ticket was written to a dispatcher's socket.
+    Delivered { ticket: Ticket },
+    /// Days a plate was already ticketed on, written by compaction.
+    Days { plate: String, days: Vec<u32> },
+    /// Tickets issued on a day that compaction dropped from the journal.
+    Issued { day: u32, tickets: usize },
+}
+
+/// Append-only JSON-lines log of everything needed to rebuild the daemon state.
+pub struct Journal {
+    path: PathBuf,
+    file: File,
+}
+
+impl Journal {
+    /// Opens the journal at `path`, creating it if needed, and returns it with
+    /// the entries already in it. A torn last line from a crash is skipped.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<(Journal, Vec<Entry>)> {
+        let path = path.as_ref().to_path_buf();
+        let mut entries = Vec::new();
+
+        if path.exists() {
+            for line in BufReader::new(File::open(&path)?).lines() {
+                match serde_json::from_str(&line?) {
+                    Ok(entry) => entries.push(entry),
+                    Err(e) => println!("Skipping bad journal line: {e}"),
+                }
+            }
+        }
+
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        Ok((Journal { path, file }, entries))
+    }
+
+    pub fn append(&mut self, entry: &Entry) -> io::Result<()> {
+        let mut line = serde_json::to_string(entry)?;
+        line.push('\n');
+        self.file.write_all(line.as_bytes())?;
+        self.file.flush()
+    }
+
+    /// Replaces the journal contents with `entries`.
+    ///
+    /// The new journal is written next to the old one and renamed over it, so
+    /// a crash midway leaves the old journal intact.
+    pub fn rewrite(&mut self, entries: &[Entry]) -> io::Result<()> {
+        let tmp = self.path.with_extension("tmp");
+
+        let mut file = File::create(&tmp)?;
+        for entry in entries {
+            let mut line = serde_json::to_string(entry)?;
+            line.push('\n');
+            file.write_all(line.as_bytes())?;
+        }
+        file.sync_all()?;
+        fs::rename(&tmp, &self.path)?;
+
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        Ok(())
+    }
+}
+
+/// Rebuilds observations and ticket bookkeeping from journal entries.
+///
+/// Observations are only recorded, the tickets they produced are already in
+/// the journal. Tickets that were never delivered end up queued again.
+pub fn replay(entries: Vec<Entry>, observations: &mut ObservationStore, ledger: &mut Ledger) {
+    for entry in entries {
+        match entry {
+            Entry::Observation {
+                road,
+                limit,
+                mile,
+                plate,
+                timestamp,
+            } => {
+                observations.record(road, limit, mile, &plate, timestamp);
+            }
+            Entry::Ticket { ticket } => ledger.restore(ticket),
+            Entry::Delivered { ticket } => ledger.remove_pending(&ticket),
+            Entry::Days { plate, days } => ledger.mark_days(&plate, days),
+            Entry::Issued { day, tickets } => ledger.count_issued(day, tickets),
+        }
+    }
+}
+
+/// Compacts the observation store and returns the minimal set of entries that
+/// rebuilds the current state.
+///
+/// `undelivered` are tickets already handed to a dispatcher but not written
+/// yet. They are kept as owed, like the ledger's pending tickets.
+pub fn compact<'a>(
+    observations: &mut ObservationStore,
+    ledger: &'a Ledger,
+    undelivered: impl IntoIterator<Item = &'a Ticket>,
+) -> Vec<Entry> {
+    observations.compact();
+
+    let days = ledger.ticketed_days().map(|(plate, days)| {
+        let mut days: Vec<u32> = days.iter().copied().collect();
+        days.sort();
+        Entry::Days {
+            plate: plate.clone(),
+            days,
+        }
+    });
+    let owed: Vec<&Ticket> = ledger.pending().chain(undelivered).collect();
+
+    // Owed tickets are counted again when their entries are replayed.
+    let mut issued = ledger.issued_per_day().clone();
+    for ticket in &owed {
+        if let Some(tickets) = issued.get_mut(ticket_days(ticket).start()) {
+            *tickets = tickets.saturating_sub(1);
+        }
+    }
+    let issued = issued
+        .into_iter()
+        .filter(|(_, tickets)| *tickets > 0)
+        .map(|(day, tickets)| Entry::Issued { day, tickets });
+
+    let sightings = observations.sightings().map(|s| Entry::Observation {
+        road: s.road,
+        limit: s.limit,
+        mile: s.mile,
+        plate: s.plate,
+        timestamp: s.timestamp,
+    });
+    let pending = owed.into_iter().map(|ticket| Entry::Ticket {
+        ticket: ticket.clone(),
+    });
+
+    days.chain(issued).chain(sightings).chain(pending).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::observations::{check_pair, max_ticket_span, DEFAULT_MAX_LATENESS};
+    use std::collections::BTreeMap;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "speed_daemon_journal_{}_{name}",
+            std::process::id()
+        ));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn observe(
+        journal: &mut Journal,
+        observations: &mut ObservationStore,
+        ledger: &mut Ledger,
+        mile: u16,
+        plate: &str,
+        timestamp: u32,
+    ) {
+        journal
+            .append(&Entry::Observation {
+                road: 1,
+                limit: 60,
+                mile,
+                plate: String::from(plate),
+                timestamp,
+            })
+            .unwrap();
+
+        for ticket in observations.record(1, 60, mile, plate, timestamp) {
+            if !ledger.is_ticketed(&ticket) {
+                journal
+                    .append(&Entry::Ticket {
+                        ticket: ticket.clone(),
+                    })
+                    .unwrap();
+                ledger.issue(ticket);
+            }
+        }
+    }
+
+    #[test]
+    fn replay_keeps_undelivered_tickets() {
+        let path = temp_path("replay");
+
+        {
+            let (mut journal, entries) = Journal::open(&path).unwrap();
+            assert!(entries.is_empty());
+
+            let mut observations = ObservationStore::new();
+            let mut ledger = Ledger::new();
+            observe(&mut journal, &mut observations, &mut ledger, 0, "A", 0);
+            observe(&mut journal, &mut observations, &mut ledger, 10, "A", 60);
+            observe(&mut journal, &mut observations, &mut ledger, 0, "B", 0);
+            observe(&mut journal, &mut observations, &mut ledger, 10, "B", 60);
+
+            let deliveries = ledger.add_dispatcher(1, &[1]);
+            assert_eq!(deliveries.len(), 2);
+            journal
+                .append(&Entry::Delivered {
+                    ticket: deliveries[0].1.clone(),
+                })
+                .unwrap();
+        }
+
+        let (_, entries) = Journal::open(&path).unwrap();
+        let mut observations = ObservationStore::new();
+        let mut ledger = Ledger::new();
+        replay(entries, &mut observations, &mut ledger);
+
+        // One ticket still owed, and no plate gets ticketed again today.
+        assert_eq!(ledger.pending().count(), 1);
+        for ticket in observations.record(1, 60, 20, "A", 120) {
+            assert!(ledger.is_ticketed(&ticket));
+        }
+        for ticket in observations.record(1, 60, 20, "B", 120) {
+            assert!(ledger.is_ticketed(&ticket));
+        }
+
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn compaction_preserves_state() {
+        let path = temp_path("compact");
+        let span = max_ticket_span(60) + DEFAULT_MAX_LATENESS;
+
+        let (mut journal, _) = Journal::open(&path).unwrap();
+        let mut observations = ObservationStore::new();
+        let mut ledger = Ledger::new();
+        observe(&mut journal, &mut observations, &mut ledger, 0, "A", 0);
+        observe(&mut journal, &mut observations, &mut ledger, 10, "A", 60);
+        observe(
+            &mut journal,
+            &mut observations,
+            &mut ledger,
+            0,
+            "B",
+            span + 100,
+        );
+
+        let entries = compact(&mut observations, &ledger, []);
+        journal.rewrite(&entries).unwrap();
+        journal
+            .append(&Entry::Observation {
+                road: 1,
+                limit: 60,
+                mile: 5,
+                plate: String::from("C"),
+                timestamp: span + 100,
+            })
+            .unwrap();
+
+        let (_, entries) = Journal::open(&path).unwrap();
+        let mut observations = ObservationStore::new();
+        let mut ledger = Ledger::new();
+        replay(entries, &mut observations, &mut ledger);
+
+        assert_eq!(observations.sightings().count(), 2);
+        assert_eq!(ledger.pending().count(), 1);
+        assert!(ledger.is_ticketed(&ledger.pending().next().unwrap().clone()));
+
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn compaction_keeps_undelivered_tickets() {
+        let path = temp_path("undelivered");
+
+        let (mut journal, _) = Journal::open(&path).unwrap();
+        let mut observations = ObservationStore::new();
+        let mut ledger = Ledger::new();
+        ledger.add_dispatcher(1, &[1]);
+        observe(&mut journal, &mut observations, &mut ledger, 0, "A", 0);
+        observe(&mut journal, &mut observations, &mut ledger, 10, "A", 60);
+        observe(&mut journal, &mut observations, &mut ledger, 0, "B", 0);
+        observe(&mut journal, &mut observations, &mut ledger, 10, "B", 60);
+
+        // Both tickets went to the dispatcher, only A's was written.
+        assert_eq!(ledger.pending().count(), 0);
+        let a = check_pair("A", 1, 60, (0, 0), (10, 60)).unwrap();
+        let b = check_pair("B", 1, 60, (0, 0), (10, 60)).unwrap();
+        journal.append(&Entry::Delivered { ticket: a }).unwrap();
+
+        let entries = compact(&mut observations, &ledger, [&b]);
+        journal.rewrite(&entries).unwrap();
+
+        let (_, entries) = Journal::open(&path).unwrap();
+        let mut ledger = Ledger::new();
+        replay(entries, &mut ObservationStore::new(), &mut ledger);
+        assert_eq!(ledger.pending().collect::<Vec<_>>(), [&b]);
+        assert_eq!(ledger.issued_per_day(), &BTreeMap::from([(0, 2)]));
+
+        let _ = fs::remove_file(&path);
+    }
+}
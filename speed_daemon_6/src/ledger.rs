@@ -0,0 +1,258 @@
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::ops::RangeInclusive;
+
+use crate::observations::Ticket;
+
+pub type DispatcherId = u64;
+
+/// A ticket and the dispatcher it should be sent to.
+pub type Delivery = (DispatcherId, Ticket);
+
+/// Days covered by a ticket, a day being `floor(timestamp / 86400)`.
+pub fn ticket_days(ticket: &Ticket) -> RangeInclusive<u32> {
+    ticket.timestamp1 / 86400..=ticket.timestamp2 / 86400
+}
+
+#[derive(Debug, Default)]
+struct Road {
+    dispatchers: Vec<DispatcherId>,
+    next: usize,
+    pending: VecDeque<Ticket>,
+}
+
+impl Road {
+    fn next_dispatcher(&mut self) -> Option<DispatcherId> {
+        if self.dispatchers.is_empty() {
+            return None;
+        }
+
+        let id = self.dispatchers[self.next % self.dispatchers.len()];
+        self.next = (self.next + 1) % self.dispatchers.len();
+        Some(id)
+    }
+}
+
+/// Decides which tickets get issued and who delivers them.
+///
+/// A plate gets at most one ticket per day. A ticket spanning several days
+/// uses up all of them. Tickets for roads without a dispatcher are queued
+/// until one connects, and roads with several dispatchers hand out tickets
+/// round-robin.
+#[derive(Debug, Default)]
+pub struct Ledger {
+    ticketed_days: HashMap<String, HashSet<u32>>,
+    /// Tickets issued per day, each counted on the day it starts.
+    issued: BTreeMap<u32, usize>,
+    roads: HashMap<u16, Road>,
+}
+
+impl Ledger {
+    pub fn new() -> Ledger {
+        Ledger::default()
+    }
+
+    /// Whether the plate already has a ticket on one of this ticket's days.
+    pub fn is_ticketed(&self, ticket: &Ticket) -> bool {
+        self.ticketed_days
+            .get(&ticket.plate)
+            .is_some_and(|days| ticket_days(ticket).any(|day| days.contains(&day)))
+    }
+
+    /// Issues a ticket unless the plate was already ticketed on one of its
+    /// days. Returns the delivery if a dispatcher is available, otherwise the
+    /// ticket is queued for its road.
+    pub fn issue(&mut self, ticket: Ticket) -> Option<Delivery> {
+        let days = self.ticketed_days.entry(ticket.plate.clone()).or_default();
+        if ticket_days(&ticket).any(|day| days.contains(&day)) {
+            return None;
+        }
+        days.extend(ticket_days(&ticket));
+        self.count_issued(*ticket_days(&ticket).start(), 1);
+
+        self.reassign(ticket)
+    }
+
+    /// Hands an issued ticket to the next dispatcher for its road, or queues
+    /// it until one connects. Used for tickets a dispatcher never received.
+    pub fn reassign(&mut self, ticket: Ticket) -> Option<Delivery> {
+        let road = self.roads.entry(ticket.road).or_default();
+        match road.next_dispatcher() {
+            Some(id) => Some((id, ticket)),
+            None => {
+                road.pending.push_back(ticket);
+                None
+            }
+        }
+    }
+
+    /// Registers a dispatcher and hands it every ticket queued for its roads.
+    pub fn add_dispatcher(&mut self, id: DispatcherId, roads: &[u16]) -> Vec<Delivery> {
+        let mut deliveries = Vec::new();
+
+        for road in roads {
+            let road = self.roads.entry(*road).or_default();
+            if road.dispatchers.contains(&id) {
+                continue;
+            }
+
+            road.dispatchers.push(id);
+            deliveries.extend(road.pending.drain(..).map(|ticket| (id, ticket)));
+        }
+
+        deliveries
+    }
+
+    pub fn remove_dispatcher(&mut self, id: DispatcherId) {
+        for road in self.roads.values_mut() {
+            road.dispatchers.retain(|d| *d != id);
+        }
+    }
+
+    /// Marks days as already ticketed for a plate.
+    pub fn mark_days(&mut self, plate: &str, days: impl IntoIterator<Item = u32>) {
+        self.ticketed_days
+            .entry(String::from(plate))
+            .or_default()
+            .extend(days);
+    }
+
+    /// Adds tickets issued on a day, for tickets rebuilt from compacted
+    /// counts rather than one by one.
+    pub fn count_issued(&mut self, day: u32, tickets: usize) {
+        *self.issued.entry(day).or_default() += tickets;
+    }
+
+    /// Queues a ticket that was issued earlier, skipping the day check.
+    pub fn restore(&mut self, ticket: Ticket) {
+        self.mark_days(&ticket.plate, ticket_days(&ticket));
+        self.count_issued(*ticket_days(&ticket).start(), 1);
+        self.roads
+            .entry(ticket.road)
+            .or_default()
+            .pending
+            .push_back(ticket);
+    }
+
+    /// Removes a queued ticket, once it is known to have been delivered.
+    pub fn remove_pending(&mut self, ticket: &Ticket) {
+        if let Some(road) = self.roads.get_mut(&ticket.road) {
+            if let Some(pos) = road.pending.iter().position(|t| t == ticket) {
+                road.pending.remove(pos);
+            }
+        }
+    }
+
+    pub fn ticketed_days(&self) -> impl Iterator<Item = (&String, &HashSet<u32>)> {
+        self.ticketed_days.iter()
+    }
+
+    pub fn issued_per_day(&self) -> &BTreeMap<u32, usize> {
+        &self.issued
+    }
+
+    pub fn pending(&self) -> impl Iterator<Item = &Ticket> {
+        self.roads.values().flat_map(|road| road.pending.iter())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ticket(plate: &str, road: u16, timestamp1: u32, timestamp2: u32) -> Ticket {
+        Ticket {
+            plate: String::from(plate),
+            road,
+            mile1: 0,
+            timestamp1,
+            mile2: 100,
+            timestamp2,
+            speed: 10000,
+        }
+    }
+
+    #[test]
+    fn one_ticket_per_day() {
+        let mut ledger = Ledger::new();
+        ledger.add_dispatcher(1, &[1]);
+
+        assert!(ledger.issue(ticket("A", 1, 0, 100)).is_some());
+        assert!(ledger.issue(ticket("A", 1, 200, 300)).is_none());
+        assert!(ledger.issue(ticket("B", 1, 200, 300)).is_some());
+        assert!(ledger.issue(ticket("A", 1, 86400, 86500)).is_some());
+    }
+
+    #[test]
+    fn multi_day_ticket_uses_every_day() {
+        let mut ledger = Ledger::new();
+        ledger.add_dispatcher(1, &[1]);
+
+        assert!(ledger.issue(ticket("A", 1, 86000, 3 * 86400)).is_some());
+        assert!(ledger
+            .issue(ticket("A", 1, 2 * 86400, 2 * 86400 + 10))
+            .is_none());
+        assert!(ledger.issue(ticket("A", 1, 0, 10)).is_none());
+        assert!(ledger
+            .issue(ticket("A", 1, 4 * 86400, 4 * 86400 + 10))
+            .is_some());
+
+        // Counted once, on the day each ticket starts.
+        assert_eq!(ledger.issued_per_day(), &BTreeMap::from([(0, 1), (4, 1)]));
+    }
+
+    #[test]
+    fn queued_until_dispatcher_connects() {
+        let mut ledger = Ledger::new();
+
+        assert!(ledger.issue(ticket("A", 1, 0, 10)).is_none());
+        assert!(ledger.issue(ticket("B", 2, 0, 10)).is_none());
+
+        assert!(ledger.add_dispatcher(1, &[3]).is_empty());
+        let deliveries = ledger.add_dispatcher(2, &[1, 2]);
+        assert_eq!(deliveries.len(), 2);
+        assert!(deliveries.iter().all(|(id, _)| *id == 2));
+
+        // Nothing is delivered twice.
+        assert!(ledger.add_dispatcher(3, &[1, 2]).is_empty());
+    }
+
+    #[test]
+    fn round_robin() {
+        let mut ledger = Ledger::new();
+        ledger.add_dispatcher(1, &[1]);
+        ledger.add_dispatcher(2, &[1]);
+
+        let ids: Vec<DispatcherId> = ["A", "B", "C", "D"]
+            .iter()
+            .map(|plate| ledger.issue(ticket(plate, 1, 0, 10)).unwrap().0)
+            .collect();
+        assert_eq!(ids, vec![1, 2, 1, 2]);
+
+        ledger.remove_dispatcher(1);
+        assert_eq!(ledger.issue(ticket("E", 1, 0, 10)).unwrap().0, 2);
+        ledger.remove_dispatcher(2);
+        assert!(ledger.issue(ticket("F", 1, 0, 10)).is_none());
+        assert_eq!(
+            ledger.add_dispatcher(3, &[1]),
+            vec![(3, ticket("F", 1, 0, 10))]
+        );
+    }
+
+    #[test]
+    fn reassign_undelivered() {
+        let mut ledger = Ledger::new();
+        ledger.add_dispatcher(1, &[1]);
+        ledger.add_dispatcher(2, &[1]);
+
+        let (id, lost) = ledger.issue(ticket("A", 1, 0, 10)).unwrap();
+        assert_eq!(id, 1);
+        ledger.remove_dispatcher(1);
+
+        // Goes to the remaining dispatcher without a second day check.
+        assert_eq!(ledger.reassign(lost.clone()), Some((2, lost.clone())));
+
+        ledger.remove_dispatcher(2);
+        assert!(ledger.reassign(lost.clone()).is_none());
+        assert_eq!(ledger.add_dispatcher(3, &[1]), vec![(3, lost)]);
+    }
+}
@@ -0,0 +1,6 @@
+pub mod admin;
+pub mod client;
+pub mod journal;
+pub mod ledger;
+pub mod message;
+pub mod observations;
@@ -1,17 +1,381 @@
-use std::net::TcpListener;
+use speed_daemon_6::admin::Snapshot;
+use speed_daemon_6::client::{Action, Client, Role};
+use speed_daemon_6::journal::{self, Entry, Journal};
+use speed_daemon_6::ledger::{Delivery, DispatcherId, Ledger};
+use speed_daemon_6::message::{self, CodecError, Message};
+use speed_daemon_6::observations::{ObservationStore, Ticket, DEFAULT_MAX_LATENESS};
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::time::{self, Instant, Interval};
 
-fn main() {
-    // Start tcp listener
-    let listener = TcpListener::bind("127.0.0.1:8080").unwrap();
-    for stream in listener.incoming() {
-        match stream {
-            Ok(stream) => {
-                println!("New connection: {}", stream.peer_addr().unwrap());
-                thread::spawn(move || {
-                    handle_connection(stream);
+const JOURNAL_PATH: &str = "speed_daemon.journal";
+const ADMIN_ADDRESS: &str = "127.0.0.1:8081";
+const USAGE: &str = "Usage: speed_daemon_6 [max sighting lateness in seconds]";
+
+/// Journal entries after which a running daemon compacts its journal, or
+/// twice as many as the last compaction left if that is more.
+const COMPACT_AFTER: usize = 100_000;
+
+struct State {
+    observations: ObservationStore,
+    ledger: Ledger,
+    journal: Journal,
+    roles: HashMap<u64, Role>,
+    dispatchers: HashMap<DispatcherId, UnboundedSender<Message>>,
+    next_id: u64,
+    /// Tickets handed to a dispatcher's writer that it hasn't written yet.
+    undelivered: Vec<Ticket>,
+    journal_entries: usize,
+    compact_at: usize,
+}
+
+impl State {
+    fn load(path: &str, max_lateness: u32) -> std::io::Result<State> {
+        let (journal, entries) = Journal::open(path)?;
+        let mut observations = ObservationStore::with_max_lateness(max_lateness);
+        let mut ledger = Ledger::new();
+
+        println!("Replaying {} journal entries", entries.len());
+        journal::replay(entries, &mut observations, &mut ledger);
+
+        let mut state = State {
+            observations,
+            ledger,
+            journal,
+            roles: HashMap::new(),
+            dispatchers: HashMap::new(),
+            next_id: 0,
+            undelivered: Vec::new(),
+            journal_entries: 0,
+            compact_at: COMPACT_AFTER,
+        };
+        state.compact()?;
+        Ok(state)
+    }
+
+    /// Rewrites the journal to the minimal entries for the current state.
+    fn compact(&mut self) -> std::io::Result<()> {
+        let entries = journal::compact(&mut self.observations, &self.ledger, &self.undelivered);
+        self.journal.rewrite(&entries)?;
+        self.journal_entries = entries.len();
+        self.compact_at = (entries.len() * 2).max(COMPACT_AFTER);
+        Ok(())
+    }
+
+    /// Compacts the journal once it has grown enough. Must only be called
+    /// between actions, when every appended entry is reflected in the state.
+    fn compact_if_due(&mut self) {
+        if self.journal_entries < self.compact_at {
+            return;
+        }
+
+        println!("Compacting {} journal entries", self.journal_entries);
+        if let Err(e) = self.compact() {
+            println!("Failed to compact journal: {e}");
+        }
+    }
+
+    fn snapshot(&self) -> Snapshot<'_> {
+        Snapshot {
+            roles: &self.roles,
+            observations: &self.observations,
+            ledger: &self.ledger,
+        }
+    }
+
+    fn append(&mut self, entry: Entry) {
+        if let Err(e) = self.journal.append(&entry) {
+            println!("Failed to write journal: {e}");
+        }
+        self.journal_entries += 1;
+    }
+
+    fn forget_undelivered(&mut self, ticket: &Ticket) {
+        if let Some(pos) = self.undelivered.iter().position(|t| t == ticket) {
+            self.undelivered.swap_remove(pos);
+        }
+    }
+
+    /// Records that a dispatcher's writer has written a ticket.
+    fn delivered(&mut self, ticket: Ticket) {
+        self.forget_undelivered(&ticket);
+        self.append(Entry::Delivered { ticket });
+    }
+
+    /// Queues tickets for their dispatchers. They are journaled as delivered
+    /// once the dispatcher's writer has written them.
+    fn deliver(&mut self, deliveries: Vec<Delivery>) {
+        let mut deliveries = VecDeque::from(deliveries);
+
+        while let Some((id, ticket)) = deliveries.pop_front() {
+            println!("Ticket for dispatcher {id}: {:?}", ticket);
+            let queued = self
+                .dispatchers
+                .get(&id)
+                .is_some_and(|dispatcher| dispatcher.send(Message::from(ticket.clone())).is_ok());
+
+            if queued {
+                self.undelivered.push(ticket);
+            } else {
+                // The dispatcher's writer is gone, give the ticket to another one.
+                self.dispatchers.remove(&id);
+                self.ledger.remove_dispatcher(id);
+                deliveries.extend(self.ledger.reassign(ticket));
+            }
+        }
+    }
+}
+
+/// Writes messages until every sender is gone. Returns the tickets that could
+/// not be written, so they can be handed to another dispatcher.
+async fn write_messages(
+    mut writer: OwnedWriteHalf,
+    mut messages: UnboundedReceiver<Message>,
+    state: Arc<Mutex<State>>,
+) -> Vec<Ticket> {
+    let mut unsent = Vec::new();
+    let mut open = true;
+
+    while let Some(msg) = messages.recv().await {
+        if !open {
+            unsent.extend(Ticket::try_from(msg).ok());
+            continue;
+        }
+
+        let is_error = matches!(msg, Message::Error { .. });
+
+        let bytes = match msg.encode() {
+            Ok(bytes) => bytes,
+            Err(_) => continue,
+        };
+
+        let written = writer.write_all(&bytes).await.is_ok();
+        match Ticket::try_from(msg) {
+            Ok(ticket) if written => state.lock().unwrap().delivered(ticket),
+            Ok(ticket) => unsent.push(ticket),
+            Err(_) => (),
+        }
+
+        if !written || is_error {
+            // Keep draining so tickets queued after this are not lost.
+            open = false;
+            let _ = writer.shutdown().await;
+        }
+    }
+
+    if open {
+        let _ = writer.shutdown().await;
+    }
+    unsent
+}
+
+async fn tick(heartbeat: &mut Option<Interval>) {
+    match heartbeat {
+        Some(interval) => {
+            interval.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+fn heartbeat_interval(deciseconds: u32) -> Option<Interval> {
+    if deciseconds == 0 {
+        return None;
+    }
+
+    let period = Duration::from_millis(deciseconds as u64 * 100);
+    Some(time::interval_at(Instant::now() + period, period))
+}
+
+fn handle_action(
+    id: u64,
+    action: Action,
+    sender: &UnboundedSender<Message>,
+    state: &Arc<Mutex<State>>,
+    heartbeat: &mut Option<Interval>,
+) {
+    match action {
+        Action::Identified(role) => {
+            println!("Connection {id} identified as {:?}", role);
+            let mut state = state.lock().unwrap();
+            state.roles.insert(id, role.clone());
+
+            if let Role::Dispatcher { roads } = role {
+                state.dispatchers.insert(id, sender.clone());
+                let deliveries = state.ledger.add_dispatcher(id, &roads);
+                state.deliver(deliveries);
+            }
+        }
+        Action::Observation {
+            road,
+            mile,
+            limit,
+            plate,
+            timestamp,
+        } => {
+            let mut state = state.lock().unwrap();
+            state.append(Entry::Observation {
+                road,
+                limit,
+                mile,
+                plate: plate.clone(),
+                timestamp,
+            });
+
+            let tickets = state
+                .observations
+                .record(road, limit, mile, &plate, timestamp);
+            let mut deliveries = Vec::new();
+            for ticket in tickets {
+                if state.ledger.is_ticketed(&ticket) {
+                    continue;
+                }
+
+                state.append(Entry::Ticket {
+                    ticket: ticket.clone(),
                 });
+                deliveries.extend(state.ledger.issue(ticket));
             }
-            Err(_) => {}
+            state.deliver(deliveries);
+            state.compact_if_due();
         }
+        Action::WantHeartbeat { interval } => *heartbeat = heartbeat_interval(interval),
+    }
+}
+
+async fn handle_messages(
+    id: u64,
+    mut reader: OwnedReadHalf,
+    sender: &UnboundedSender<Message>,
+    state: &Arc<Mutex<State>>,
+) {
+    let mut buf = Vec::new();
+    let mut client = Client::new();
+    let mut heartbeat = None;
+
+    loop {
+        let msg = tokio::select! {
+            msg = message::read_message(&mut reader, &mut buf) => msg,
+            () = tick(&mut heartbeat) => {
+                let _ = sender.send(Message::Heartbeat);
+                continue;
+            }
+        };
+
+        let msg = match msg {
+            Ok(Some(msg)) => msg,
+            Ok(None) => return,
+            Err(CodecError::Io(kind)) => {
+                println!("Connection {id} failed: {kind}");
+                return;
+            }
+            Err(e) => {
+                let _ = sender.send(Message::Error { msg: e.to_string() });
+                return;
+            }
+        };
+
+        match client.handle(msg) {
+            Ok(action) => handle_action(id, action, sender, state, &mut heartbeat),
+            Err(e) => {
+                let _ = sender.send(Message::Error { msg: e });
+                return;
+            }
+        }
+    }
+}
+
+async fn handle_connection(stream: TcpStream, state: Arc<Mutex<State>>) {
+    let (reader, writer) = stream.into_split();
+    let (sender, receiver) = mpsc::unbounded_channel();
+    let writer = tokio::spawn(write_messages(writer, receiver, state.clone()));
+
+    let id = {
+        let mut state = state.lock().unwrap();
+        state.next_id += 1;
+        state.next_id
+    };
+
+    handle_messages(id, reader, &sender, &state).await;
+
+    {
+        let mut state = state.lock().unwrap();
+        state.roles.remove(&id);
+        state.ledger.remove_dispatcher(id);
+        state.dispatchers.remove(&id);
+    }
+
+    // Let the writer flush whatever is queued, it stops once every sender is gone.
+    drop(sender);
+    let unsent = writer.await.unwrap_or_default();
+
+    if !unsent.is_empty() {
+        let mut state = state.lock().unwrap();
+        let mut deliveries = Vec::new();
+        for ticket in unsent {
+            state.forget_undelivered(&ticket);
+            deliveries.extend(state.ledger.reassign(ticket));
+        }
+        state.deliver(deliveries);
+    }
+}
+
+async fn handle_admin(stream: TcpStream, state: Arc<Mutex<State>>) {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+
+    while let Ok(Some(line)) = lines.next_line().await {
+        let mut response = state.lock().unwrap().snapshot().answer(&line).to_string();
+        response.push('\n');
+
+        if writer.write_all(response.as_bytes()).await.is_err() {
+            return;
+        }
+    }
+}
+
+async fn serve_admin(listener: TcpListener, state: Arc<Mutex<State>>) {
+    loop {
+        let (stream, addr) = listener.accept().await.unwrap();
+        println!("Admin connection: {}", addr);
+        let state = state.clone();
+        tokio::spawn(async move {
+            handle_admin(stream, state).await;
+        });
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let max_lateness = match std::env::args().nth(1).map(|arg| arg.parse()) {
+        None => DEFAULT_MAX_LATENESS,
+        Some(Ok(seconds)) => seconds,
+        Some(Err(_)) => {
+            println!("{USAGE}");
+            return;
+        }
+    };
+
+    // Start tcp listener
+    let listener = TcpListener::bind("127.0.0.1:8080").await.unwrap();
+    let admin = TcpListener::bind(ADMIN_ADDRESS).await.unwrap();
+    let state = Arc::new(Mutex::new(State::load(JOURNAL_PATH, max_lateness).unwrap()));
+
+    tokio::spawn(serve_admin(admin, state.clone()));
+
+    loop {
+        let (stream, addr) = listener.accept().await.unwrap();
+        println!("New connection: {}", addr);
+        let state = state.clone();
+        tokio::spawn(async move {
+            handle_connection(stream, state).await;
+        });
     }
 }
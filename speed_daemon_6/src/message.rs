@@ -0,0 +1,380 @@
+use std::{fmt, io};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const MSG_ERROR: u8 = 0x10;
+const MSG_PLATE: u8 = 0x20;
+const MSG_TICKET: u8 = 0x21;
+const MSG_WANT_HEARTBEAT: u8 = 0x40;
+const MSG_HEARTBEAT: u8 = 0x41;
+const MSG_I_AM_CAMERA: u8 = 0x80;
+const MSG_I_AM_DISPATCHER: u8 = 0x81;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    Error {
+        msg: String,
+    },
+    Plate {
+        plate: String,
+        timestamp: u32,
+    },
+    Ticket {
+        plate: String,
+        road: u16,
+        mile1: u16,
+        timestamp1: u32,
+        mile2: u16,
+        timestamp2: u32,
+        speed: u16,
+    },
+    WantHeartbeat {
+        interval: u32,
+    },
+    Heartbeat,
+    IAmCamera {
+        road: u16,
+        mile: u16,
+        limit: u16,
+    },
+    IAmDispatcher {
+        roads: Vec<u16>,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CodecError {
+    /// The buffer ends before the frame does, more bytes are needed.
+    Incomplete,
+    /// The first byte of the frame is not a known message type.
+    UnknownType(u8),
+    /// A str field contains non-ASCII bytes.
+    InvalidString,
+    /// A field does not fit in its wire representation.
+    TooLong,
+    /// Reading from the connection failed, e.g. it was reset.
+    Io(io::ErrorKind),
+}
+
+impl fmt::Display for CodecError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CodecError::Incomplete => write!(f, "Incomplete message"),
+            CodecError::UnknownType(t) => write!(f, "Unknown message type 0x{t:02x}"),
+            CodecError::InvalidString => write!(f, "Invalid string"),
+            CodecError::TooLong => write!(f, "Field too long"),
+            CodecError::Io(kind) => write!(f, "Read failed: {kind}"),
+        }
+    }
+}
+
+impl std::error::Error for CodecError {}
+
+struct Cursor<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Cursor<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], CodecError> {
+        let bytes = self
+            .buf
+            .get(self.pos..self.pos + len)
+            .ok_or(CodecError::Incomplete)?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, CodecError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, CodecError> {
+        Ok(u16::from_be_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    fn u32(&mut self) -> Result<u32, CodecError> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn str(&mut self) -> Result<String, CodecError> {
+        let len = self.u8()? as usize;
+        let bytes = self.take(len)?;
+
+        if !bytes.is_ascii() {
+            return Err(CodecError::InvalidString);
+        }
+
+        Ok(bytes.iter().map(|b| *b as char).collect())
+    }
+}
+
+fn put_str(out: &mut Vec<u8>, s: &str) -> Result<(), CodecError> {
+    let len = u8::try_from(s.len()).map_err(|_| CodecError::TooLong)?;
+    if !s.is_ascii() {
+        return Err(CodecError::InvalidString);
+    }
+
+    out.push(len);
+    out.extend_from_slice(s.as_bytes());
+    Ok(())
+}
+
+impl Message {
+    /// Decodes a single message from the front of `buf`.
+    ///
+    /// On success returns the message and the number of bytes it occupied.
+    /// `CodecError::Incomplete` means the frame is valid so far but more bytes
+    /// are needed, any other error means the stream is garbage.
+    pub fn decode(buf: &[u8]) -> Result<(Message, usize), CodecError> {
+        let mut cur = Cursor { buf, pos: 0 };
+
+        let msg = match cur.u8()? {
+            MSG_ERROR => Message::Error { msg: cur.str()? },
+            MSG_PLATE => Message::Plate {
+                plate: cur.str()?,
+                timestamp: cur.u32()?,
+            },
+            MSG_TICKET => Message::Ticket {
+                plate: cur.str()?,
+                road: cur.u16()?,
+                mile1: cur.u16()?,
+                timestamp1: cur.u32()?,
+                mile2: cur.u16()?,
+                timestamp2: cur.u32()?,
+                speed: cur.u16()?,
+            },
+            MSG_WANT_HEARTBEAT => Message::WantHeartbeat {
+                interval: cur.u32()?,
+            },
+            MSG_HEARTBEAT => Message::Heartbeat,
+            MSG_I_AM_CAMERA => Message::IAmCamera {
+                road: cur.u16()?,
+                mile: cur.u16()?,
+                limit: cur.u16()?,
+            },
+            MSG_I_AM_DISPATCHER => {
+                let numroads = cur.u8()?;
+                let roads = (0..numroads)
+                    .map(|_| cur.u16())
+                    .collect::<Result<Vec<u16>, CodecError>>()?;
+                Message::IAmDispatcher { roads }
+            }
+            other => return Err(CodecError::UnknownType(other)),
+        };
+
+        Ok((msg, cur.pos))
+    }
+
+    /// Encodes the message into its wire representation.
+    pub fn encode(&self) -> Result<Vec<u8>, CodecError> {
+        let mut out = Vec::new();
+
+        match self {
+            Message::Error { msg } => {
+                out.push(MSG_ERROR);
+                put_str(&mut out, msg)?;
+            }
+            Message::Plate { plate, timestamp } => {
+                out.push(MSG_PLATE);
+                put_str(&mut out, plate)?;
+                out.extend_from_slice(&timestamp.to_be_bytes());
+            }
+            Message::Ticket {
+                plate,
+                road,
+                mile1,
+                timestamp1,
+                mile2,
+                timestamp2,
+                speed,
+            } => {
+                out.push(MSG_TICKET);
+                put_str(&mut out, plate)?;
+                out.extend_from_slice(&road.to_be_bytes());
+                out.extend_from_slice(&mile1.to_be_bytes());
+                out.extend_from_slice(&timestamp1.to_be_bytes());
+                out.extend_from_slice(&mile2.to_be_bytes());
+                out.extend_from_slice(&timestamp2.to_be_bytes());
+                out.extend_from_slice(&speed.to_be_bytes());
+            }
+            Message::WantHeartbeat { interval } => {
+                out.push(MSG_WANT_HEARTBEAT);
+                out.extend_from_slice(&interval.to_be_bytes());
+            }
+            Message::Heartbeat => out.push(MSG_HEARTBEAT),
+            Message::IAmCamera { road, mile, limit } => {
+                out.push(MSG_I_AM_CAMERA);
+                out.extend_from_slice(&road.to_be_bytes());
+                out.extend_from_slice(&mile.to_be_bytes());
+                out.extend_from_slice(&limit.to_be_bytes());
+            }
+            Message::IAmDispatcher { roads } => {
+                out.push(MSG_I_AM_DISPATCHER);
+                out.push(u8::try_from(roads.len()).map_err(|_| CodecError::TooLong)?);
+                for road in roads {
+                    out.extend_from_slice(&road.to_be_bytes());
+                }
+            }
+        }
+
+        Ok(out)
+    }
+}
+
+/// Reads the next message from `reader`, keeping partial frames in `buf`.
+///
+/// Returns `Ok(None)` once the stream is closed and `CodecError::Io` if
+/// reading fails any other way. Cancelling the future loses nothing, so it can
+/// be used in `tokio::select!`.
+pub async fn read_message<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    buf: &mut Vec<u8>,
+) -> Result<Option<Message>, CodecError> {
+    loop {
+        match Message::decode(buf) {
+            Ok((msg, used)) => {
+                buf.drain(..used);
+                return Ok(Some(msg));
+            }
+            Err(CodecError::Incomplete) => (),
+            Err(e) => return Err(e),
+        }
+
+        let mut chunk = [0; 512];
+        match reader.read(&mut chunk).await {
+            Ok(0) => return Ok(None),
+            Ok(n) => buf.extend_from_slice(&chunk[..n]),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(CodecError::Io(e.kind())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_spec_examples() {
+        let plate = [0x20, 0x04, 0x55, 0x4e, 0x31, 0x58, 0x00, 0x00, 0x03, 0xe8];
+        assert_eq!(
+            Message::decode(&plate),
+            Ok((
+                Message::Plate {
+                    plate: String::from("UN1X"),
+                    timestamp: 1000
+                },
+                plate.len()
+            ))
+        );
+
+        let camera = [0x80, 0x00, 0x42, 0x00, 0x64, 0x00, 0x3c];
+        assert_eq!(
+            Message::decode(&camera),
+            Ok((
+                Message::IAmCamera {
+                    road: 66,
+                    mile: 100,
+                    limit: 60
+                },
+                camera.len()
+            ))
+        );
+
+        let dispatcher = [0x81, 0x03, 0x00, 0x42, 0x01, 0x70, 0x13, 0x88];
+        assert_eq!(
+            Message::decode(&dispatcher),
+            Ok((
+                Message::IAmDispatcher {
+                    roads: vec![66, 368, 5000]
+                },
+                dispatcher.len()
+            ))
+        );
+    }
+
+    #[test]
+    fn encode_ticket() {
+        let ticket = Message::Ticket {
+            plate: String::from("UN1X"),
+            road: 66,
+            mile1: 100,
+            timestamp1: 123456,
+            mile2: 110,
+            timestamp2: 123816,
+            speed: 10000,
+        };
+        let expected = [
+            0x21, 0x04, 0x55, 0x4e, 0x31, 0x58, 0x00, 0x42, 0x00, 0x64, 0x00, 0x01, 0xe2, 0x40,
+            0x00, 0x6e, 0x00, 0x01, 0xe3, 0xa8, 0x27, 0x10,
+        ];
+
+        let encoded = ticket.encode().unwrap();
+        assert_eq!(encoded, expected);
+        assert_eq!(Message::decode(&encoded), Ok((ticket, expected.len())));
+    }
+
+    #[test]
+    fn truncated_and_unknown() {
+        let plate = [0x20, 0x04, 0x55, 0x4e, 0x31, 0x58, 0x00, 0x00, 0x03, 0xe8];
+        for len in 0..plate.len() {
+            assert_eq!(Message::decode(&plate[..len]), Err(CodecError::Incomplete));
+        }
+
+        assert_eq!(Message::decode(&[0x42]), Err(CodecError::UnknownType(0x42)));
+        assert_eq!(
+            Message::decode(&[0x10, 0x01, 0xff]),
+            Err(CodecError::InvalidString)
+        );
+    }
+
+    #[test]
+    fn trailing_bytes_are_left() {
+        let buf = [0x41, 0x40, 0x00, 0x00, 0x00, 0x0a];
+        let (msg, used) = Message::decode(&buf).unwrap();
+        assert_eq!(msg, Message::Heartbeat);
+        assert_eq!(
+            Message::decode(&buf[used..]),
+            Ok((Message::WantHeartbeat { interval: 10 }, 5))
+        );
+    }
+
+    /// Fails every read with the given error.
+    struct Failing(io::ErrorKind);
+
+    impl AsyncRead for Failing {
+        fn poll_read(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+            _buf: &mut tokio::io::ReadBuf<'_>,
+        ) -> std::task::Poll<io::Result<()>> {
+            std::task::Poll::Ready(Err(self.0.into()))
+        }
+    }
+
+    #[test]
+    fn read_errors_are_not_eof() {
+        let rt = tokio::runtime::Runtime::new().unwrap();
+        rt.block_on(async {
+            let mut buf = vec![0x41, 0x40];
+            let mut stream: &[u8] = &[0x00, 0x00, 0x00, 0x0a];
+            assert_eq!(
+                read_message(&mut stream, &mut buf).await,
+                Ok(Some(Message::Heartbeat))
+            );
+            assert_eq!(
+                read_message(&mut stream, &mut buf).await,
+                Ok(Some(Message::WantHeartbeat { interval: 10 }))
+            );
+            assert_eq!(read_message(&mut stream, &mut buf).await, Ok(None));
+
+            let mut reset = Failing(io::ErrorKind::ConnectionReset);
+            assert_eq!(
+                read_message(&mut reset, &mut buf).await,
+                Err(CodecError::Io(io::ErrorKind::ConnectionReset))
+            );
+            let mut eof = Failing(io::ErrorKind::UnexpectedEof);
+            assert_eq!(read_message(&mut eof, &mut buf).await, Ok(None));
+        });
+    }
+}
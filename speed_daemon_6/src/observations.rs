@@ -0,0 +1,364 @@
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+
+use crate::message::Message;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Ticket {
+    pub plate: String,
+    pub road: u16,
+    pub mile1: u16,
+    pub timestamp1: u32,
+    pub mile2: u16,
+    pub timestamp2: u32,
+    /// Average speed in hundredths of a mile per hour.
+    pub speed: u16,
+}
+
+impl From<Ticket> for Message {
+    fn from(ticket: Ticket) -> Message {
+        Message::Ticket {
+            plate: ticket.plate,
+            road: ticket.road,
+            mile1: ticket.mile1,
+            timestamp1: ticket.timestamp1,
+            mile2: ticket.mile2,
+            timestamp2: ticket.timestamp2,
+            speed: ticket.speed,
+        }
+    }
+}
+
+impl TryFrom<Message> for Ticket {
+    type Error = Message;
+
+    fn try_from(msg: Message) -> Result<Ticket, Message> {
+        match msg {
+            Message::Ticket {
+                plate,
+                road,
+                mile1,
+                timestamp1,
+                mile2,
+                timestamp2,
+                speed,
+            } => Ok(Ticket {
+                plate,
+                road,
+                mile1,
+                timestamp1,
+                mile2,
+                timestamp2,
+                speed,
+            }),
+            msg => Err(msg),
+        }
+    }
+}
+
+/// Checks a pair of sightings against the road limit.
+///
+/// The sightings may be given in any order. Returns a ticket when the average
+/// speed between them is at least half a mile per hour over the limit.
+pub fn check_pair(
+    plate: &str,
+    road: u16,
+    limit: u16,
+    (mile_a, timestamp_a): (u16, u32),
+    (mile_b, timestamp_b): (u16, u32),
+) -> Option<Ticket> {
+    let ((mile1, timestamp1), (mile2, timestamp2)) = if timestamp_a <= timestamp_b {
+        ((mile_a, timestamp_a), (mile_b, timestamp_b))
+    } else {
+        ((mile_b, timestamp_b), (mile_a, timestamp_a))
+    };
+
+    if timestamp1 == timestamp2 {
+        return None;
+    }
+
+    let distance = mile1.abs_diff(mile2) as u64;
+    let time = (timestamp2 - timestamp1) as u64;
+
+    // distance / time * 3600 >= limit + 0.5, scaled by 100 to stay in integers.
+    if distance * 3600 * 100 < (limit as u64 * 100 + 50) * time {
+        return None;
+    }
+
+    let speed = (distance * 3600 * 100 + time / 2) / time;
+
+    Some(Ticket {
+        plate: String::from(plate),
+        road,
+        mile1,
+        timestamp1,
+        mile2,
+        timestamp2,
+        speed: speed.min(u16::MAX as u64) as u16,
+    })
+}
+
+/// Longest time in seconds between two sightings on a road that can still
+/// prove speeding, given that miles are at most `u16::MAX` apart.
+pub fn max_ticket_span(limit: u16) -> u32 {
+    let span = u16::MAX as u64 * 3600 * 100 / (limit as u64 * 100 + 50);
+    span.min(u32::MAX as u64) as u32
+}
+
+/// How far behind the newest sighting a late one may arrive and still be
+/// checked against everything it could pair with, unless the store is built
+/// with [`ObservationStore::with_max_lateness`].
+///
+/// Any sighting could pair with one that arrives arbitrarily late, so keeping
+/// everything that might still produce a ticket means keeping everything.
+/// Bounding the lateness is a deliberate trade-off: a sighting that arrives
+/// later than this misses tickets against sightings compaction has already
+/// dropped, in exchange for memory and journal size that stay proportional to
+/// recent traffic.
+pub const DEFAULT_MAX_LATENESS: u32 = 86400;
+
+/// A single camera sighting.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Sighting {
+    pub road: u16,
+    pub limit: u16,
+    pub mile: u16,
+    pub plate: String,
+    pub timestamp: u32,
+}
+
+/// Every sighting reported by cameras, indexed by road and plate.
+#[derive(Debug)]
+pub struct ObservationStore {
+    limits: HashMap<u16, u16>,
+    newest: u32,
+    max_lateness: u32,
+    sightings: HashMap<(u16, String), BTreeMap<u32, u16>>,
+}
+
+impl Default for ObservationStore {
+    fn default() -> ObservationStore {
+        ObservationStore::with_max_lateness(DEFAULT_MAX_LATENESS)
+    }
+}
+
+impl ObservationStore {
+    pub fn new() -> ObservationStore {
+        ObservationStore::default()
+    }
+
+    /// A store whose compaction keeps enough history for sightings arriving up
+    /// to `max_lateness` seconds behind the newest one, see
+    /// [`DEFAULT_MAX_LATENESS`].
+    pub fn with_max_lateness(max_lateness: u32) -> ObservationStore {
+        ObservationStore {
+            limits: HashMap::new(),
+            newest: 0,
+            max_lateness,
+            sightings: HashMap::new(),
+        }
+    }
+
+    /// Records a sighting and returns a ticket for every earlier sighting of
+    /// the same plate on the same road that it proves the car was speeding.
+    pub fn record(
+        &mut self,
+        road: u16,
+        limit: u16,
+        mile: u16,
+        plate: &str,
+        timestamp: u32,
+    ) -> Vec<Ticket> {
+        self.limits.insert(road, limit);
+        self.newest = self.newest.max(timestamp);
+
+        let sightings = self
+            .sightings
+            .entry((road, String::from(plate)))
+            .or_default();
+
+        if sightings.contains_key(&timestamp) {
+            return Vec::new();
+        }
+
+        let tickets = sightings
+            .iter()
+            .filter_map(|(other_timestamp, other_mile)| {
+                check_pair(
+                    plate,
+                    road,
+                    limit,
+                    (*other_mile, *other_timestamp),
+                    (mile, timestamp),
+                )
+            })
+            .collect();
+
+        sightings.insert(timestamp, mile);
+        tickets
+    }
+
+    pub fn sightings(&self) -> impl Iterator<Item = Sighting> + '_ {
+        self.sightings
+            .iter()
+            .flat_map(move |((road, plate), sightings)| {
+                let limit = self.limits.get(road).copied().unwrap_or_default();
+                sightings.iter().map(move |(timestamp, mile)| Sighting {
+                    road: *road,
+                    limit,
+                    mile: *mile,
+                    plate: plate.clone(),
+                    timestamp: *timestamp,
+                })
+            })
+    }
+
+    /// Drops sightings that can no longer take part in a ticket.
+    ///
+    /// Sightings are assumed to arrive at most the store's maximum lateness
+    /// behind the newest one already recorded. A sighting further back than that plus
+    /// `max_ticket_span` for its road is then too far away in time from any
+    /// future sighting to prove speeding. Returns how many sightings were
+    /// dropped.
+    pub fn compact(&mut self) -> usize {
+        let mut dropped = 0;
+
+        for ((road, _), sightings) in self.sightings.iter_mut() {
+            let limit = self.limits.get(road).copied().unwrap_or_default();
+            let oldest = self
+                .newest
+                .saturating_sub(self.max_lateness)
+                .saturating_sub(max_ticket_span(limit));
+            let before = sightings.len();
+            sightings.retain(|timestamp, _| *timestamp >= oldest);
+            dropped += before - sightings.len();
+        }
+
+        self.sightings.retain(|_, sightings| !sightings.is_empty());
+        dropped
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spec_example() {
+        let mut store = ObservationStore::new();
+
+        assert!(store.record(123, 60, 8, "UN1X", 0).is_empty());
+        assert_eq!(
+            store.record(123, 60, 9, "UN1X", 45),
+            vec![Ticket {
+                plate: String::from("UN1X"),
+                road: 123,
+                mile1: 8,
+                timestamp1: 0,
+                mile2: 9,
+                timestamp2: 45,
+                speed: 8000,
+            }]
+        );
+    }
+
+    #[test]
+    fn out_of_order() {
+        let mut store = ObservationStore::new();
+
+        assert!(store.record(123, 60, 9, "UN1X", 45).is_empty());
+        let tickets = store.record(123, 60, 8, "UN1X", 0);
+        assert_eq!(tickets.len(), 1);
+        assert_eq!((tickets[0].mile1, tickets[0].timestamp1), (8, 0));
+        assert_eq!((tickets[0].mile2, tickets[0].timestamp2), (9, 45));
+    }
+
+    #[test]
+    fn half_mph_threshold() {
+        // 1 mile in 60 seconds is exactly the limit.
+        assert!(check_pair("A", 1, 60, (0, 0), (1, 60)).is_none());
+        // 121 miles in 7200 seconds is 60.5 mph.
+        let ticket = check_pair("A", 1, 60, (0, 0), (121, 7200)).unwrap();
+        assert_eq!(ticket.speed, 6050);
+        // 60.49 mph is not enough.
+        assert!(check_pair("A", 1, 60, (0, 0), (6049, 360000)).is_none());
+    }
+
+    #[test]
+    fn separate_roads_and_plates() {
+        let mut store = ObservationStore::new();
+
+        store.record(1, 60, 0, "A", 0);
+        assert!(store.record(2, 60, 10, "A", 60).is_empty());
+        assert!(store.record(1, 60, 10, "B", 60).is_empty());
+        assert_eq!(store.record(1, 60, 10, "A", 60).len(), 1);
+    }
+
+    #[test]
+    fn compact_drops_unreachable_sightings() {
+        let mut store = ObservationStore::new();
+        let span = max_ticket_span(60) + DEFAULT_MAX_LATENESS;
+
+        store.record(1, 60, 0, "A", 0);
+        store.record(1, 60, 0, "A", 10);
+        store.record(1, 60, 0, "B", 10);
+        store.record(1, 60, 0, "A", span + 10);
+
+        assert_eq!(store.compact(), 1);
+        assert_eq!(store.sightings().count(), 3);
+        assert!(store.sightings().all(|s| s.timestamp >= 10));
+
+        // The edge of the span can still produce a ticket.
+        assert_eq!(
+            store
+                .record(1, 60, u16::MAX, "B", max_ticket_span(60) + 10)
+                .len(),
+            1
+        );
+    }
+
+    #[test]
+    fn late_sighting_after_compact() {
+        let mut store = ObservationStore::new();
+        let span = max_ticket_span(60);
+
+        store.record(1, 60, 0, "A", 1000);
+        store.record(1, 60, 0, "B", 1000 + span + DEFAULT_MAX_LATENESS);
+        assert_eq!(store.compact(), 0);
+
+        // Arrives as late as allowed and pairs with the oldest kept sighting.
+        let tickets = store.record(1, 60, u16::MAX, "A", 1000 + span);
+        assert_eq!(tickets.len(), 1);
+        assert_eq!(tickets[0].timestamp1, 1000);
+    }
+
+    #[test]
+    fn configured_lateness() {
+        let span = max_ticket_span(60);
+
+        // Without any allowance a sighting only stays while a future one
+        // could still pair with it.
+        let mut store = ObservationStore::with_max_lateness(0);
+        store.record(1, 60, 0, "A", 1000);
+        store.record(1, 60, 0, "B", 1000 + span);
+        assert_eq!(store.compact(), 0);
+        store.record(1, 60, 0, "B", 1001 + span);
+        assert_eq!(store.compact(), 1);
+
+        // A later straggler then misses the ticket it would have proven.
+        assert!(store.record(1, 60, u16::MAX, "A", 1000 + span).is_empty());
+
+        let mut store = ObservationStore::with_max_lateness(3600);
+        store.record(1, 60, 0, "A", 1000);
+        store.record(1, 60, 0, "B", 1000 + span + 3600);
+        assert_eq!(store.compact(), 0);
+        assert_eq!(store.record(1, 60, u16::MAX, "A", 1000 + span).len(), 1);
+    }
+
+    #[test]
+    fn driving_backwards() {
+        let ticket = check_pair("A", 1, 60, (10, 0), (0, 60)).unwrap();
+        assert_eq!((ticket.mile1, ticket.mile2), (10, 0));
+        assert_eq!(ticket.speed, 60000);
+    }
+}
@@ -1,7 +1,29 @@
 use std::{collections::HashMap, net::UdpSocket};
 
-fn main() {
-    let udp_listener = UdpSocket::bind("0.0.0.0:10000").unwrap();
+fn handle_request(database: &mut HashMap<String, String>, msg: String) -> Option<String> {
+    if msg.contains('=') {
+        // Insert request
+        let (key, value) = msg.split_once('=').unwrap();
+
+        // Ignore version requests
+        if key == "version" {
+            return None;
+        }
+
+        database.insert(String::from(key), String::from(value));
+        None
+    } else {
+        // Retrieve request
+        let value = match database.get(msg.as_str()) {
+            Some(v) => v,
+            None => "",
+        };
+
+        Some(msg + "=" + value)
+    }
+}
+
+fn serve(udp_listener: UdpSocket) {
     let mut database: HashMap<String, String> = HashMap::new();
     let mut buf = [0; 1000];
 
@@ -10,27 +32,144 @@ fn main() {
     loop {
         let (size, src) = udp_listener.recv_from(&mut buf).unwrap();
         println!("Received {size} bytes from {src}");
-        let msg = String::from_utf8(buf[..size].to_vec()).unwrap();
 
-        if msg.contains('=') {
-            // Insert request
-            let (key, value) = msg.split_once('=').unwrap();
+        // Drop requests that are not valid UTF-8, e.g. cut short mid-character.
+        let msg = match String::from_utf8(buf[..size].to_vec()) {
+            Ok(msg) => msg,
+            Err(_) => continue,
+        };
+
+        if let Some(response) = handle_request(&mut database, msg) {
+            let _ = udp_listener.send_to(response.as_bytes(), src);
+        }
+    }
+}
+
+fn main() {
+    let udp_listener = UdpSocket::bind("0.0.0.0:10000").unwrap();
+    serve(udp_listener);
+}
 
-            // Ignore version requests
-            if key == "version" {
-                continue;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lossy_udp::{Impairments, LossyProxy};
+    use std::net::SocketAddr;
+    use std::thread;
+    use std::time::Duration;
+
+    fn start_server() -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let addr = socket.local_addr().unwrap();
+        thread::spawn(move || serve(socket));
+        addr
+    }
+
+    fn client(addr: SocketAddr) -> UdpSocket {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.connect(addr).unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_millis(50)))
+            .unwrap();
+        socket
+    }
+
+    /// Sends `request` until a response equal to `expected` comes back, the
+    /// way a client on an unreliable network has to. Returns the attempts used.
+    fn retry(socket: &UdpSocket, request: &[&str], expected: &str) -> Option<usize> {
+        let mut buf = [0; 1000];
+
+        for attempt in 1..=50 {
+            for part in request {
+                socket.send(part.as_bytes()).unwrap();
+            }
+
+            while let Ok(size) = socket.recv(&mut buf) {
+                if &buf[..size] == expected.as_bytes() {
+                    return Some(attempt);
+                }
             }
+        }
 
-            database.insert(String::from(key), String::from(value));
-        } else {
-            // Retrieve request
-            let value = match database.get(msg.as_str()) {
-                Some(v) => v,
-                None => "",
-            };
+        None
+    }
 
-            let response = msg + "=" + value;
-            let _ = udp_listener.send_to(response.as_bytes(), src);
+    #[test]
+    fn requests() {
+        let mut database = HashMap::new();
+        database.insert(String::from("version"), String::from("1.2.3"));
+
+        assert_eq!(handle_request(&mut database, String::from("foo=bar")), None);
+        assert_eq!(
+            handle_request(&mut database, String::from("foo")),
+            Some(String::from("foo=bar"))
+        );
+        assert_eq!(
+            handle_request(&mut database, String::from("foo=bar=baz")),
+            None
+        );
+        assert_eq!(
+            handle_request(&mut database, String::from("foo")),
+            Some(String::from("foo=bar=baz"))
+        );
+        assert_eq!(
+            handle_request(&mut database, String::from("missing")),
+            Some(String::from("missing="))
+        );
+        assert_eq!(
+            handle_request(&mut database, String::from("version=9")),
+            None
+        );
+        assert_eq!(
+            handle_request(&mut database, String::from("version")),
+            Some(String::from("version=1.2.3"))
+        );
+    }
+
+    #[test]
+    fn lossy_network() {
+        let impairments = Impairments {
+            loss: 0.3,
+            duplicate: 0.3,
+            reorder: 0.3,
+            delay: Duration::from_millis(2),
+            jitter: Duration::from_millis(5),
+            seed: 4,
+            ..Default::default()
+        };
+        let proxy = LossyProxy::start(start_server(), impairments).unwrap();
+        let socket = client(proxy.local_addr());
+
+        for i in 0..20 {
+            let insert = format!("key{i}=value {i}");
+            let key = format!("key{i}");
+            assert!(retry(&socket, &[&insert, &key], &insert).is_some());
         }
+
+        assert!(retry(&socket, &["version"], "version=1.2.3").is_some());
+        let stats = proxy.stats();
+        assert!(stats.dropped > 0 && stats.duplicated > 0 && stats.reordered > 0);
+    }
+
+    #[test]
+    fn survives_truncated_packets() {
+        let server = start_server();
+        let impairments = Impairments {
+            truncate: 1.0,
+            seed: 1,
+            ..Default::default()
+        };
+        let proxy = LossyProxy::start(server, impairments).unwrap();
+        let socket = client(proxy.local_addr());
+
+        // Multi-byte characters get cut in half, which is not valid UTF-8.
+        for _ in 0..20 {
+            socket.send("ключ=значение".as_bytes()).unwrap();
+        }
+        thread::sleep(Duration::from_millis(50));
+        assert!(proxy.stats().truncated >= 20);
+
+        let direct = client(server);
+        assert_eq!(retry(&direct, &["version"], "version=1.2.3"), Some(1));
     }
 }